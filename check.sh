@@ -23,13 +23,15 @@ function main() {
         cargo build
     )
 
+    # If evaluation fails, still publish the report explaining why
+    check_status=0
     cargo run -- \
         --subset "$SUBSET" \
         --rev "$REV" \
         --sha256 "$HASH" \
         --max-cores 48 \
         --max-cores-per-job 4 \
-        check
+        check || check_status=$?
 
     cargo run -- \
         --subset "$SUBSET" \
@@ -41,6 +43,8 @@ function main() {
 
     tar -cJf "./$REPORT_NAME.tar.xz" "./$REPORT_NAME"
     buildkite-agent artifact upload "./$REPORT_NAME.tar.xz"
+
+    return "$check_status"
 }
 
 main "$1" "$2"
@@ -1,19 +1,37 @@
 use log::debug;
 
+use chrono::{DateTime, Utc};
 use itertools::Itertools;
-use structopt::StructOpt;
+use structopt::{clap, StructOpt};
 
 use r13y::{
-    check::check,
-    messages::{Attr, BuildRequest, BuildRequestV1, Subset},
+    cas::{Compression, LocalStorage, Location},
+    check::{check, CheckError, CheckOptions},
+    coordinator::{self, CoordinatorConfig},
+    eval::EvalError,
+    gc::{self, GcOptions},
+    keys,
+    messages::{Attr, BuildRequest, BuildRequestV2, Overlay, Subset, Variation},
     report::report,
+    verify::{self, VerifierConfig, VerifyError},
 };
 
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+use tempdir::TempDir;
+
 #[derive(StructOpt, Debug)]
 struct Opt {
     #[structopt(flatten)]
     nixpkgs: Nixpkgs,
 
+    #[structopt(flatten)]
+    request: RequestOptions,
+
     #[structopt(long = "result-url")]
     result_url: Option<String>,
 
@@ -25,23 +43,113 @@ struct Opt {
     #[structopt(long = "max-cores-per-job", default_value = "1")]
     maximum_cores_per_job: u16,
 
+    /// Seconds a --check build may take before it is set aside until
+    /// everything else is checked. 0, the default, means no limit.
+    #[structopt(long = "timeout", default_value = "0")]
+    timeout: u64,
+    /// Seconds a set aside --check build may take on its second try
+    /// before it is reported as timed out. Unlimited by default.
+    #[structopt(long = "slow-timeout")]
+    slow_timeout: Option<u64>,
+    /// How many times to --check each build. Builds which only differ
+    /// sometimes are reported as flaky.
+    #[structopt(long = "rounds", default_value = "1")]
+    rounds: u32,
+
     /// Which subsets of nixpkgs to test.
-    /// Format: `subset:attr.path | subset`.
-    /// subset can be either of "nixpkgs" or "nixos",
+    /// Format: `subset:attr.path | subset | flake:ref#outputs.attr.path | flake:ref#outputs`.
+    /// subset can be either of "nixpkgs", "nixos" or "nixpkgs-overlays",
     /// attr.path is a dot-delimited attribute path into the preceding subset.
+    /// ref is a flake reference locked to a commit, e.g. github:owner/repo/<commit>,
+    /// and outputs is "packages" or "nixosConfigurations".
     #[structopt(short = "s", long = "subset", parse(try_from_str = "parse_subset"))]
     subsets: Vec<(Subset, Attr)>,
+
+    /// Seconds running builds may take to finish after SIGINT or
+    /// SIGTERM before they are killed
+    #[structopt(long = "grace-period", default_value = "60")]
+    grace_period: u64,
+
+    /// Where to store the NARs of builds, and where the report reads
+    /// them from. A directory, or a bucket of an S3-compatible object
+    /// store with credentials in AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY.
+    /// Format: `directory | s3+https://host/bucket/prefix | s3+http://host:port/bucket/prefix`.
+    #[structopt(long = "cas", default_value = "./tmp/")]
+    cas: Location,
+
+    /// Where NARs are hashed and compressed before they are uploaded
+    /// to S3 storage
+    #[structopt(long = "cas-scratch", default_value = "./tmp/", parse(from_os_str))]
+    cas_scratch: PathBuf,
+
+    /// How to store the NARs of builds.
+    /// Format: `none | zstd | zstd:level`.
+    #[structopt(
+        long = "cas-compression",
+        default_value = "none",
+        parse(try_from_str = "parse_compression")
+    )]
+    cas_compression: Compression,
+
+    /// Re-hash stored NARs before diffing or uploading them, and
+    /// quarantine ones which don't match
+    #[structopt(long = "verify-cas-reads")]
+    verify_cas_reads: bool,
+
+    /// SQLite database the results are kept in
+    #[structopt(long = "database", default_value = "./r13y.sqlite", parse(from_os_str))]
+    database: PathBuf,
+
+    /// Directory of the reproducibility-log-<rev>.json files older
+    /// versions kept results in, imported into --database
+    #[structopt(long = "legacy-logs", default_value = ".", parse(from_os_str))]
+    legacy_logs: PathBuf,
 }
 
 #[derive(StructOpt, Debug)]
 struct Nixpkgs {
     /// Nixpkgs revision to use, e.g. 70503758fb4b37107953dfb03ad7c0cf36ad0435
     #[structopt(long = "rev")]
-    rev: String,
+    rev: Option<String>,
     /// SHA-256 hashsum of tarball of the given Nixpkgs revision,
     /// e.g. 15g8xckhzpp84p6gv526hb6c1r286qvn8i14w8msw6172jy3kj3c
     #[structopt(long = "sha256")]
-    sha256: String,
+    sha256: Option<String>,
+    /// Evaluate this local checkout of Nixpkgs, e.g. a git worktree,
+    /// instead of fetching the revision. --rev defaults to the
+    /// checkout's HEAD and --sha256 isn't needed.
+    #[structopt(long = "nixpkgs-path", parse(from_os_str))]
+    path: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Debug)]
+struct RequestOptions {
+    /// System to evaluate and build for
+    #[structopt(long = "system", default_value = "x86_64-linux")]
+    system: String,
+    /// Identifier of the build request. Defaults to the revision and
+    /// a hash of the rest of the request
+    #[structopt(long = "request-id")]
+    request_id: Option<String>,
+    /// Don't start builds after this time, e.g. 2019-06-01T00:00:00Z
+    #[structopt(long = "deadline", parse(try_from_str = "parse_deadline"))]
+    deadline: Option<DateTime<Utc>>,
+    /// How the second build should differ from the first.
+    /// Format: `disorderfs | cores:count`.
+    #[structopt(
+        long = "variation",
+        parse(try_from_str = "parse_variation"),
+        raw(number_of_values = "1")
+    )]
+    variations: Vec<Variation>,
+    /// An overlay for the nixpkgs-overlays subset, a tarball whose
+    /// default.nix is the overlay. Format: `url#sha256`.
+    #[structopt(
+        long = "overlay",
+        parse(try_from_str = "parse_overlay"),
+        raw(number_of_values = "1")
+    )]
+    overlays: Vec<Overlay>,
 }
 
 #[derive(StructOpt, Debug)]
@@ -50,14 +158,135 @@ enum Mode {
     Check,
     #[structopt(name = "report")]
     Report,
+    /// Publish the build request to verifiers and collect their responses
+    #[structopt(name = "serve-coordinator")]
+    ServeCoordinator(ServeCoordinator),
+    /// Fetch the build request from a coordination server, check it,
+    /// and send the results back
+    #[structopt(name = "verify")]
+    Verify(Verify),
+    /// Generate a signing key and print its public key
+    #[structopt(name = "keygen")]
+    Keygen {
+        /// Where to write the new secret key
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Delete stored NARs and diffs which no recent result refers to
+    #[structopt(name = "gc")]
+    Gc(Gc),
+    /// Maintain the stored NARs
+    #[structopt(name = "cas")]
+    Cas(Cas),
+}
+
+#[derive(StructOpt, Debug)]
+enum Cas {
+    /// Re-hash every stored file, quarantine the ones which don't match
+    /// their id, and remove leftover scratch directories
+    #[structopt(name = "fsck")]
+    Fsck {
+        /// Storage directory to check. Defaults to --cas and ./report/cas
+        #[structopt(long = "dir", parse(from_os_str), raw(number_of_values = "1"))]
+        dirs: Vec<PathBuf>,
+    },
+    /// Move files stored directly in the storage directory into the
+    /// sharded layout
+    #[structopt(name = "migrate")]
+    Migrate {
+        /// Storage directory to migrate. Defaults to --cas and ./report/cas
+        #[structopt(long = "dir", parse(from_os_str), raw(number_of_values = "1"))]
+        dirs: Vec<PathBuf>,
+    },
+}
+
+/// The storage directories a `cas` command works on, the --dir ones
+/// or else --cas and the report's
+fn cas_dirs(dirs: Vec<PathBuf>, cas: &Location, command: &str) -> Vec<PathBuf> {
+    if dirs.is_empty() {
+        vec![local_cas(cas, command).to_path_buf(), PathBuf::from("./report/cas")]
+    } else {
+        dirs
+    }
+}
+
+/// The directory of --cas, for commands which list or delete what is
+/// stored. They can't in S3 storage, so it is refused.
+fn local_cas<'a>(cas: &'a Location, command: &str) -> &'a Path {
+    cas.directory().unwrap_or_else(|| {
+        clap::Error::with_description(
+            &format!("`{}` only works on a --cas directory, not S3 storage", command),
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit()
+    })
+}
+
+#[derive(StructOpt, Debug)]
+struct Gc {
+    /// Keep what the results of this many of the most recently
+    /// requested revisions refer to
+    #[structopt(long = "keep-revisions", default_value = "5")]
+    keep_revisions: u32,
+    /// Keep what results recorded within this many days refer to,
+    /// and anything written within them
+    #[structopt(long = "keep-days", default_value = "30")]
+    keep_days: u32,
+    /// Only print what would be deleted
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+}
+
+#[derive(StructOpt, Debug)]
+struct ServeCoordinator {
+    #[structopt(long = "listen", default_value = "127.0.0.1:8080")]
+    listen: String,
+    /// URL verifiers reach this server at. Defaults to http://<listen>
+    #[structopt(long = "public-url")]
+    public_url: Option<String>,
+    /// Secret key to sign the build request and upload tokens with
+    #[structopt(long = "signing-key", parse(from_os_str))]
+    signing_key: PathBuf,
+    /// Hex-encoded public key of a verifier to accept responses from
+    #[structopt(long = "trusted-key", raw(required = "true"))]
+    trusted_keys: Vec<String>,
+    /// Directory for received responses and uploads
+    #[structopt(long = "state-dir", default_value = "./coordinator", parse(from_os_str))]
+    state_dir: PathBuf,
+    #[structopt(long = "threads", default_value = "4")]
+    threads: u16,
+    /// Largest NAR verifiers may upload, in GiB
+    #[structopt(long = "max-upload-gib", default_value = "16")]
+    max_upload_gib: u64,
+}
+
+#[derive(StructOpt, Debug)]
+struct Verify {
+    /// Base URL of the coordination server, e.g. https://compute.r13y.com
+    #[structopt(long = "coordinator")]
+    coordinator: String,
+    /// Hex-encoded public key the build request must be signed with
+    #[structopt(long = "coordinator-key", raw(required = "true"))]
+    coordinator_keys: Vec<String>,
+    /// Secret key to sign our responses with
+    #[structopt(long = "signing-key", parse(from_os_str))]
+    signing_key: PathBuf,
+    /// Where responses wait until the coordination server accepts them
+    #[structopt(long = "outbox", default_value = "./verifier-outbox.jsonl", parse(from_os_str))]
+    outbox: PathBuf,
 }
 
 fn parse_subset(s: &str) -> Result<(Subset, Attr), &'static str> {
+    if let Some(flake) = s.strip_prefix("flake:") {
+        return parse_flake_subset(flake);
+    }
+
     let mut comp = s.split(':');
 
     let subset = match comp.next() {
         Some("nixpkgs") => Subset::Nixpkgs,
         Some("nixos") => Subset::NixOSReleaseCombined,
+        Some("nixpkgs-overlays") => Subset::NixpkgsWithOverlays,
         Some(_) => return Err("unknown subset specifier"),
         None => return Err("no subset specifier"),
     };
@@ -71,14 +300,101 @@ fn parse_subset(s: &str) -> Result<(Subset, Attr), &'static str> {
     Ok((subset, attr_path))
 }
 
-fn main() {
-    env_logger::init();
-    let opt = Opt::from_args();
+fn parse_flake_subset(s: &str) -> Result<(Subset, Attr), &'static str> {
+    let (flake, fragment) = s.rsplit_once('#').ok_or("flake subset without outputs")?;
+    let mut comp = fragment.splitn(2, '.');
 
-    debug!("Using options: {:#?}", opt);
+    let subset = Subset::Flake {
+        flake: flake.parse()?,
+        outputs: comp.next().unwrap_or_default().parse()?,
+    };
+
+    let attr_path = if let Some(attrs) = comp.next() {
+        attrs.split('.').map(str::to_owned).collect()
+    } else {
+        Vec::new()
+    };
+
+    Ok((subset, attr_path))
+}
+
+fn parse_deadline(s: &str) -> Result<DateTime<Utc>, chrono::ParseError> {
+    DateTime::parse_from_rfc3339(s).map(|deadline| deadline.with_timezone(&Utc))
+}
+
+fn parse_overlay(s: &str) -> Result<Overlay, &'static str> {
+    match s.rsplit_once('#') {
+        Some((url, _)) if !url.starts_with("https://") => Err("overlays must be https:// URLs"),
+        Some((url, sha256)) if !sha256.is_empty() => Ok(Overlay {
+            url: url.to_string(),
+            sha256: sha256.to_string(),
+        }),
+        _ => Err("overlay needs a sha256, e.g. https://example.org/overlay.tar.gz#<sha256>"),
+    }
+}
+
+fn parse_compression(s: &str) -> Result<Compression, &'static str> {
+    let mut comp = s.splitn(2, ':');
+
+    match (comp.next(), comp.next()) {
+        (Some("none"), None) => Ok(Compression::None),
+        (Some("zstd"), None) => Ok(Compression::Zstd { level: 0 }),
+        (Some("zstd"), Some(level)) => level
+            .parse()
+            .map(|level| Compression::Zstd { level })
+            .map_err(|_| "zstd level must be a number"),
+        _ => Err("unknown compression"),
+    }
+}
+
+fn parse_variation(s: &str) -> Result<Variation, &'static str> {
+    let mut comp = s.splitn(2, ':');
 
-    let subsets = opt
-        .subsets
+    match (comp.next(), comp.next()) {
+        (Some("disorderfs"), None) => Ok(Variation::Disorderfs),
+        (Some("build-time"), _) => Err("build-time isn't supported yet, the clock of a sandboxed build can't be shifted"),
+        (Some("timezone" | "locale" | "hostname" | "user" | "umask"), _) => {
+            Err("the sandbox pins the time zone, locale, hostname, user and umask, they can't be varied yet")
+        }
+        (Some("cores"), Some(cores)) => match cores.parse() {
+            Ok(0) | Err(_) => Err("cores must be a positive number"),
+            Ok(cores) => Ok(Variation::Cores { cores }),
+        },
+        (Some("cores"), None) => Err("cores needs a number of cores"),
+        _ => Err("unknown variation"),
+    }
+}
+
+fn build_request(
+    nixpkgs: Nixpkgs,
+    nixpkgs_path: Option<&Path>,
+    request: RequestOptions,
+    subsets: Vec<(Subset, Attr)>,
+    result_url: String,
+) -> BuildRequest {
+    // Requests only checking flakes don't fetch Nixpkgs, the revision
+    // just names their results.
+    let only_flakes = subsets
+        .iter()
+        .all(|(subset, _)| matches!(subset, Subset::Flake { .. }));
+    let first_flake_revision = subsets.iter().find_map(|(subset, _)| match subset {
+        Subset::Flake { flake, .. } => Some(flake.revision().to_string()),
+        _ => None,
+    });
+    let rev = match nixpkgs_path {
+        Some(path) => checkout_revision(path, nixpkgs.rev),
+        None => nixpkgs.rev,
+    };
+    let rev = rev
+        .or_else(|| first_flake_revision.filter(|_| only_flakes))
+        .unwrap_or_else(|| missing_argument("--rev"));
+    let sha256 = match nixpkgs.sha256 {
+        Some(sha256) => sha256,
+        None if nixpkgs_path.is_some() || only_flakes => String::new(),
+        None => missing_argument("--sha256"),
+    };
+
+    let subsets = subsets
         .into_iter()
         .into_group_map()
         .into_iter()
@@ -92,17 +408,390 @@ fn main() {
         })
         .collect();
 
-    let instruction = BuildRequest::V1(BuildRequestV1 {
-        nixpkgs_revision: opt.nixpkgs.rev,
-        nixpkgs_sha256sum: opt.nixpkgs.sha256,
-        result_url: opt.result_url.unwrap_or_else(|| String::from("bogus")),
+    let mut v2 = BuildRequestV2 {
+        request_id: String::new(),
+        nixpkgs_revision: rev,
+        nixpkgs_sha256sum: sha256,
+        result_url,
         subsets,
+        system: request.system,
+        deadline: request.deadline,
+        variations: request.variations,
+        overlays: request.overlays,
+    };
+    // The same request run again resumes rather than starting over
+    v2.request_id = match request.request_id {
+        Some(request_id) => request_id,
+        None => v2
+            .content_id()
+            .expect("Failed to serialize the build request"),
+    };
+    BuildRequest::V2(v2)
+}
+
+/// The revision results of the checkout at `path` are recorded under:
+/// `rev`, or else the commit checked out, followed by the hash of the
+/// tree of the files git tracks if they differ from it. So results of
+/// a patched checkout get their own request id and aren't mixed up
+/// with the commit's. None without `rev` if it isn't a git checkout.
+fn checkout_revision(path: &Path, rev: Option<String>) -> Option<String> {
+    let rev = match rev {
+        Some(rev) => rev,
+        None => git(path, &["rev-parse", "HEAD"], None)?,
+    };
+    let tree = match working_tree(path) {
+        Some(tree) => tree,
+        None => return Some(rev),
+    };
+    if git(path, &["rev-parse", &format!("{}^{{tree}}", rev)], None).as_ref() == Some(&tree) {
+        Some(rev)
+    } else {
+        Some(format!("{}-dirty-{}", rev, &tree[..12]))
+    }
+}
+
+/// The hash of the tree of the files git tracks in the checkout at
+/// `path` as they are now, staged or not. Written using a copy of the
+/// index, so the checkout's own index isn't touched.
+fn working_tree(path: &Path) -> Option<String> {
+    let tmpdir = TempDir::new("r13y-tree").ok()?;
+    let index = tmpdir.path().join("index");
+    let own_index = path.join(git(path, &["rev-parse", "--git-path", "index"], None)?);
+    if own_index.exists() {
+        fs::copy(own_index, &index).ok()?;
+    }
+    git(path, &["add", "--update"], Some(&index))?;
+    git(path, &["write-tree"], Some(&index))
+}
+
+/// What git prints when run in `path`, if it succeeds.
+fn git(path: &Path, args: &[&str], index: Option<&Path>) -> Option<String> {
+    let mut git = Command::new("git");
+    git.arg("-C").arg(path).args(args);
+    if let Some(index) = index {
+        git.env("GIT_INDEX_FILE", index);
+    }
+    let output = git.output().ok().filter(|output| output.status.success())?;
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn missing_argument(name: &str) -> ! {
+    clap::Error::with_description(
+        &format!("The argument '{}' is required for this mode", name),
+        clap::ErrorKind::MissingRequiredArgument,
+    )
+    .exit()
+}
+
+fn evaluation_failed(e: EvalError) -> ! {
+    for failure in e.failures {
+        eprintln!(
+            "Evaluating {:?} {:?} failed:\n{}",
+            failure.subset, failure.attrs, failure.stderr
+        );
+    }
+    std::process::exit(1)
+}
+
+fn interrupted() -> ! {
+    eprintln!("Interrupted, run again to check the rest");
+    std::process::exit(1)
+}
+
+fn main() {
+    env_logger::init();
+    let opt = Opt::from_args();
+
+    debug!("Using options: {:#?}", opt);
+
+    let Opt {
+        nixpkgs,
+        request,
+        result_url,
+        mode,
+        subsets,
+        maximum_cores,
+        maximum_cores_per_job,
+        timeout,
+        slow_timeout,
+        rounds,
+        grace_period,
+        cas,
+        cas_scratch,
+        cas_compression,
+        verify_cas_reads,
+        database,
+        legacy_logs,
+    } = opt;
+
+    // Only opened by the modes which store or read NARs, so the ones
+    // which refuse S3 storage do so before asking for its credentials
+    let open_cas = || {
+        cas.open(cas_compression, verify_cas_reads, &cas_scratch)
+            .unwrap_or_else(|e| panic!("Unable to open --cas: {:?}", e))
+    };
+
+    let nixpkgs_path = nixpkgs.path.as_ref().map(|path| {
+        fs::canonicalize(path)
+            .unwrap_or_else(|e| panic!("Unable to find --nixpkgs-path {:?}: {}", path, e))
     });
+    let local_nixpkgs = |mode: &str| {
+        if nixpkgs_path.is_some() {
+            clap::Error::with_description(
+                &format!("{} can't evaluate a local --nixpkgs-path", mode),
+                clap::ErrorKind::ArgumentConflict,
+            )
+            .exit()
+        }
+    };
+
+    let check_options = || CheckOptions {
+        maximum_cores,
+        maximum_cores_per_job,
+        timeout: Some(timeout).filter(|timeout| *timeout > 0),
+        slow_timeout,
+        rounds: rounds.max(1),
+        database: database.clone(),
+        legacy_logs: legacy_logs.clone(),
+        grace_period: Duration::from_secs(grace_period),
+        cas: open_cas(),
+        nixpkgs_path: nixpkgs_path.clone(),
+    };
 
-    debug!("Using instruction: {:#?}", instruction);
+    match mode {
+        Mode::Check => {
+            let instruction = build_request(
+                nixpkgs,
+                nixpkgs_path.as_deref(),
+                request,
+                subsets,
+                result_url.unwrap_or_else(|| String::from("bogus")),
+            );
+            debug!("Using instruction: {:#?}", instruction);
+            match check(instruction, &check_options(), |_| ()) {
+                Ok(()) => {}
+                Err(CheckError::Eval(e)) => evaluation_failed(e),
+                Err(CheckError::Interrupted) => interrupted(),
+            }
+        }
+        Mode::Report => {
+            let instruction = build_request(
+                nixpkgs,
+                nixpkgs_path.as_deref(),
+                request,
+                subsets,
+                result_url.unwrap_or_else(|| String::from("bogus")),
+            );
+            debug!("Using instruction: {:#?}", instruction);
+            report(
+                instruction,
+                nixpkgs_path.as_deref(),
+                &database,
+                &legacy_logs,
+                &*open_cas(),
+            )
+        }
+        Mode::ServeCoordinator(serve) => {
+            // Verifiers evaluate the revision themselves
+            local_nixpkgs("serve-coordinator");
+            let listen = serve.listen;
+            let public_url = serve
+                .public_url
+                .unwrap_or_else(|| format!("http://{}", listen));
+            let instruction = build_request(
+                nixpkgs,
+                nixpkgs_path.as_deref(),
+                request,
+                subsets,
+                result_url.unwrap_or_else(|| format!("{}/result", public_url)),
+            );
+            debug!("Using instruction: {:#?}", instruction);
+
+            let trusted_keys = serve
+                .trusted_keys
+                .iter()
+                .map(|key| keys::parse_public_key(key).expect("Invalid --trusted-key"))
+                .collect();
+            coordinator::serve(
+                instruction,
+                CoordinatorConfig {
+                    listen,
+                    public_url,
+                    signing_key: keys::load_signing_key(&serve.signing_key)
+                        .expect("Unable to load --signing-key"),
+                    trusted_keys,
+                    state_dir: serve.state_dir,
+                    threads: serve.threads,
+                    max_upload_bytes: serve.max_upload_gib.saturating_mul(1 << 30),
+                },
+            )
+            .expect("Coordination server failed")
+        }
+        Mode::Verify(verify) => {
+            // Requests name the revision the coordinator checks
+            local_nixpkgs("verify");
+            let coordinator_keys = verify
+                .coordinator_keys
+                .iter()
+                .map(|key| keys::parse_public_key(key).expect("Invalid --coordinator-key"))
+                .collect();
+            let verified = verify::verify(VerifierConfig {
+                coordinator: verify.coordinator,
+                coordinator_keys,
+                signing_key: keys::load_signing_key(&verify.signing_key)
+                    .expect("Unable to load --signing-key"),
+                outbox: verify.outbox,
+                check: check_options(),
+            });
+            match verified {
+                Ok(()) => {}
+                Err(VerifyError::Eval(e)) => evaluation_failed(e),
+                Err(VerifyError::Interrupted) => interrupted(),
+                Err(e) => panic!("Verification failed: {:?}", e),
+            }
+        }
+        Mode::Keygen { path } => {
+            let key = keys::generate_signing_key();
+            keys::write_signing_key(&path, &key).expect("Unable to write the signing key");
+            println!("{}", keys::encode_public_key(&key.verifying_key()));
+        }
+        Mode::Cas(Cas::Fsck { dirs }) => {
+            let mut failed = 0;
+            for dir in cas_dirs(dirs, &cas, "cas fsck") {
+                let fsck = LocalStorage::new(dir.clone())
+                    .fsck()
+                    .unwrap_or_else(|e| panic!("Checking {:?} failed: {:?}", dir, e));
+                println!(
+                    "{:?}: checked {} files, quarantined {}, removed {} scratch directories, {} errors",
+                    dir,
+                    fsck.checked,
+                    fsck.quarantined.len(),
+                    fsck.scratch_removed,
+                    fsck.errors
+                );
+                for path in fsck.quarantined.iter() {
+                    println!("  quarantined {:?}", path);
+                }
+                failed += fsck.quarantined.len() + fsck.errors;
+            }
+            if failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Mode::Cas(Cas::Migrate { dirs }) => {
+            for dir in cas_dirs(dirs, &cas, "cas migrate") {
+                let moved = LocalStorage::new(dir.clone())
+                    .migrate()
+                    .unwrap_or_else(|e| panic!("Migrating {:?} failed: {:?}", dir, e));
+                println!("{:?}: moved {} files", dir, moved);
+            }
+        }
+        Mode::Gc(options) => {
+            let freed = gc::gc(&GcOptions {
+                keep_revisions: options.keep_revisions,
+                keep_days: options.keep_days,
+                dry_run: options.dry_run,
+                database,
+                legacy_logs,
+                cas: local_cas(&cas, "gc").to_path_buf(),
+                report_dir: PathBuf::from("./report"),
+            })
+            .expect("Garbage collection failed");
+            println!(
+                "{} {} in {} files",
+                if options.dry_run { "Would free" } else { "Freed" },
+                gc::format_bytes(freed.bytes),
+                freed.files
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_variations_are_parsed() {
+        assert_eq!(parse_variation("disorderfs"), Ok(Variation::Disorderfs));
+        assert_eq!(parse_variation("cores:4"), Ok(Variation::Cores { cores: 4 }));
+    }
+
+    #[test]
+    fn unsupported_variations_are_refused() {
+        for variation in [
+            "build-time:3600",
+            "build-time",
+            "timezone:UTC",
+            "locale",
+            "hostname:other",
+            "user",
+            "umask:077",
+            "cores:0",
+            "cores",
+            "disorderfs:yes",
+            "faketime",
+        ]
+        .iter()
+        {
+            assert!(parse_variation(variation).is_err(), "accepted {}", variation);
+        }
+    }
+
+    fn run_git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.name=r13y", "-c", "user.email=r13y@example.com"])
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    #[test]
+    fn patched_checkouts_are_named_after_their_tree() {
+        let dir = TempDir::new("nixpkgs").unwrap();
+        let checkout = dir.path();
+        run_git(checkout, &["init", "--quiet"]);
+        fs::write(checkout.join("default.nix"), "{ }").unwrap();
+        run_git(checkout, &["add", "."]);
+        run_git(checkout, &["commit", "--quiet", "-m", "init"]);
+        let head = git(checkout, &["rev-parse", "HEAD"], None).unwrap();
+        assert_eq!(checkout_revision(checkout, None), Some(head.clone()));
+
+        // Untracked files aren't evaluated
+        fs::write(checkout.join("notes.txt"), "untracked").unwrap();
+        assert_eq!(checkout_revision(checkout, None), Some(head.clone()));
+
+        fs::write(checkout.join("default.nix"), "{ patched }").unwrap();
+        let patched = checkout_revision(checkout, None).unwrap();
+        assert!(patched.starts_with(&format!("{}-dirty-", head)), "{}", patched);
+        // Staging the patch changes nothing, patching it again does
+        run_git(checkout, &["add", "default.nix"]);
+        assert_eq!(checkout_revision(checkout, None), Some(patched.clone()));
+        fs::write(checkout.join("default.nix"), "{ patched again }").unwrap();
+        let again = checkout_revision(checkout, None).unwrap();
+        assert!(again.starts_with(&format!("{}-dirty-", head)), "{}", again);
+        assert_ne!(again, patched);
+        // Without staging the second patch in the checkout's own index
+        let unstaged = Command::new("git")
+            .arg("-C")
+            .arg(checkout)
+            .args(["diff", "--quiet"])
+            .status()
+            .unwrap();
+        assert!(!unstaged.success());
+    }
 
-    match opt.mode {
-        Mode::Check => check(instruction, opt.maximum_cores, opt.maximum_cores_per_job),
-        Mode::Report => report(instruction),
+    #[test]
+    fn directories_which_arent_git_checkouts_keep_the_given_revision() {
+        let dir = TempDir::new("nixpkgs").unwrap();
+        fs::write(dir.path().join("default.nix"), "{ }").unwrap();
+        assert_eq!(checkout_revision(dir.path(), None), None);
+        assert_eq!(
+            checkout_revision(dir.path(), Some("abc".to_string())),
+            Some("abc".to_string())
+        );
     }
 }
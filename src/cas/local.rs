@@ -0,0 +1,478 @@
+//! Files stored in a local directory.
+//!
+//! Files from before the two level layout, kept directly in the root,
+//! are still found, until `migrate` moves them.
+//!
+//! Nothing is re-hashed when it is looked up, unless the storage is
+//! set to verify reads. `fsck` re-hashes everything, and moves what
+//! doesn't match its id to `quarantine/`.
+
+use sha2::{Digest, Sha256};
+
+use super::{
+    is_sha256, shard, stored_name, write_scratch, Compression, ContentAddressedStorage,
+    DigestWriter, ID,
+};
+
+use std::{
+    fs::{create_dir_all, read_dir, remove_dir_all, remove_file, rename, DirEntry, File},
+    io::{self, BufReader, Read},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// Scratch directories untouched for this long are left over from a
+/// crash, not in use by a running `store_from`
+const STALE_SCRATCH: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+    compression: Compression,
+    verify_reads: bool,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> LocalStorage {
+        LocalStorage {
+            root,
+            compression: Compression::None,
+            verify_reads: false,
+        }
+    }
+
+    /// Re-hash files when they are looked up by `str_to_id`, and
+    /// quarantine them if they don't match.
+    pub fn with_verify_reads(self, verify_reads: bool) -> LocalStorage {
+        LocalStorage {
+            verify_reads,
+            ..self
+        }
+    }
+
+    pub fn with_compression(self, compression: Compression) -> LocalStorage {
+        LocalStorage {
+            compression,
+            ..self
+        }
+    }
+
+    /// Move a file which doesn't match its id out of the way, to
+    /// `quarantine/` for a closer look. Files quarantined before under
+    /// the same name are kept, the new one gets a numbered suffix.
+    pub fn quarantine(&self, id: &ID) -> Result<PathBuf, io::Error> {
+        let path = id.path().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "only local files can be quarantined")
+        })?;
+        let quarantine = self.root.join("quarantine");
+        create_dir_all(&quarantine)?;
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let mut dest = quarantine.join(&name);
+        let mut suffix = 0;
+        while dest.symlink_metadata().is_ok() {
+            suffix += 1;
+            dest = quarantine.join(format!("{}.{}", name, suffix));
+        }
+        rename(path, &dest)?;
+        Ok(dest)
+    }
+
+    /// Re-hash everything stored, quarantining what doesn't match its
+    /// id, and remove scratch directories left behind by crashes.
+    /// Files which can't be checked are counted and skipped.
+    pub fn fsck(&self) -> Result<Fsck, io::Error> {
+        let mut fsck = Fsck::default();
+        if !self.root.exists() {
+            return Ok(fsck);
+        }
+
+        for id in self.ids()? {
+            fsck.checked += 1;
+            match id.verify() {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!("{:?} doesn't match its id", id.path());
+                    match self.quarantine(&id) {
+                        Ok(dest) => fsck.quarantined.push(dest),
+                        Err(e) => {
+                            warn!("Failed to quarantine {:?}: {:?}", id.path(), e);
+                            fsck.errors += 1;
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed to verify {:?}: {:?}", id.path(), e);
+                    fsck.errors += 1;
+                }
+            }
+        }
+
+        for entry in read_dir(&self.root)? {
+            match remove_stale_scratch(entry) {
+                Ok(true) => fsck.scratch_removed += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    warn!("Failed to remove a leftover scratch directory: {:?}", e);
+                    fsck.errors += 1;
+                }
+            }
+        }
+
+        Ok(fsck)
+    }
+
+    /// The directory `id` is stored in
+    fn shard(&self, id: &str) -> PathBuf {
+        self.root.join(shard(id))
+    }
+
+    /// Everything stored, in either layout. Anything else in the
+    /// directory, like the scratch space of an unfinished `store_from`,
+    /// is left out.
+    pub fn ids(&self) -> Result<Vec<ID>, io::Error> {
+        let mut ids = Vec::new();
+        if !self.root.exists() {
+            return Ok(ids);
+        }
+
+        ids_in(&self.root, &mut ids)?;
+        for first in shards_in(&self.root)? {
+            for second in shards_in(&first)? {
+                ids_in(&second, &mut ids)?;
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Move files kept directly in the root into their shard. Reading
+    /// works throughout, as both layouts are looked in. Files stored
+    /// again in their shard since are removed from the root, the copy
+    /// in the shard is the one found first.
+    pub fn migrate(&self) -> Result<usize, io::Error> {
+        let mut flat = Vec::new();
+        if self.root.exists() {
+            ids_in(&self.root, &mut flat)?;
+        }
+
+        let mut moved = 0;
+        for id in flat.iter() {
+            let path = id.path().expect("listed from the root");
+            let shard = self.shard(id.id());
+            let dest = shard.join(path.file_name().unwrap());
+            if dest.exists() {
+                info!("{:?} is already stored in its shard, removing it", path);
+                remove_file(path)?;
+                continue;
+            }
+            create_dir_all(&shard)?;
+            rename(path, dest)?;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+
+    /// Move a file which should hash to `expected` into the store,
+    /// without copying it. The file must be on the same filesystem,
+    /// and is kept uncompressed.
+    pub fn adopt(&self, path: &Path, expected: &str) -> Result<ID, io::Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut digest = Sha256::new();
+        io::copy(&mut reader, &mut DigestWriter(&mut digest))?;
+
+        let id = format!("{:x}", digest.result());
+        if id != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected {} but the content hashes to {}", expected, id),
+            ));
+        }
+
+        let shard = self.shard(&id);
+        create_dir_all(&shard)?;
+        let dest = shard.join(&id);
+        rename(path, &dest)?;
+
+        Ok(ID::in_file(id, dest))
+    }
+}
+
+impl ContentAddressedStorage for LocalStorage {
+    fn store_from(&self, reader: &mut dyn Read) -> Result<ID, io::Error> {
+        let scratch = write_scratch(reader, &self.root, self.compression)?;
+        let shard = self.shard(&scratch.id);
+        create_dir_all(&shard)?;
+        let compressed = matches!(self.compression, Compression::Zstd { .. });
+        let dest = shard.join(stored_name(&scratch.id, compressed));
+        rename(&scratch.file, &dest)?;
+
+        Ok(ID::in_file(scratch.id, dest))
+    }
+
+    fn str_to_id(&self, id: &str) -> Result<Option<ID>, io::Error> {
+        if !is_sha256(id) {
+            return Ok(None);
+        }
+
+        let shard = self.shard(id);
+        let candidates = [
+            shard.join(id),
+            shard.join(format!("{}.zst", id)),
+            self.root.join(id),
+            self.root.join(format!("{}.zst", id)),
+        ];
+        for path in candidates.iter() {
+            if !path.exists() {
+                continue;
+            }
+            let found = ID::in_file(id.to_string(), path.clone());
+            if !self.verify_reads {
+                return Ok(Some(found));
+            }
+
+            match found.verify() {
+                Ok(true) => return Ok(Some(found)),
+                Ok(false) => {
+                    warn!("{:?} doesn't match its id, quarantining it", path);
+                    if let Err(e) = self.quarantine(&found) {
+                        warn!("Failed to quarantine {:?}: {:?}", path, e);
+                    }
+                }
+                Err(e) => warn!("Failed to verify {:?}: {:?}", path, e),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// What `fsck` found
+#[derive(Debug, Default)]
+pub struct Fsck {
+    pub checked: usize,
+    /// Where the files which didn't match their id were moved
+    pub quarantined: Vec<PathBuf>,
+    pub scratch_removed: usize,
+    /// Files which couldn't be checked or quarantined, and scratch
+    /// directories which couldn't be removed
+    pub errors: usize,
+}
+
+/// The stored files directly in `dir`
+fn ids_in(dir: &Path, ids: &mut Vec<ID>) -> Result<(), io::Error> {
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        let id = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.strip_suffix(".zst").unwrap_or(name),
+            None => continue,
+        };
+        if is_sha256(id) && path.is_file() {
+            ids.push(ID::in_file(id.to_string(), path.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// The shard directories directly in `dir`, named by two hex digits
+fn shards_in(dir: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let mut shards = Vec::new();
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let is_shard = name.to_str().is_some_and(|name| {
+            name.len() == 2 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        });
+        if is_shard && entry.file_type()?.is_dir() {
+            shards.push(entry.path());
+        }
+    }
+    Ok(shards)
+}
+
+/// Remove `entry` if it is a scratch directory left behind by a crash,
+/// telling whether it was
+fn remove_stale_scratch(entry: io::Result<DirEntry>) -> Result<bool, io::Error> {
+    let entry = entry?;
+    let is_scratch = entry.file_type()?.is_dir()
+        && entry.file_name().to_string_lossy().starts_with("cas-scratch");
+    if !is_scratch || last_modified(&entry.path())?.elapsed().unwrap_or_default() <= STALE_SCRATCH {
+        return Ok(false);
+    }
+
+    info!("Removing leftover {:?}", entry.path());
+    remove_dir_all(entry.path())?;
+    Ok(true)
+}
+
+/// When `path`, or anything directly inside it, was last written
+fn last_modified(path: &Path) -> Result<SystemTime, io::Error> {
+    let mut modified = path.metadata()?.modified()?;
+    for entry in read_dir(path)? {
+        modified = modified.max(entry?.metadata()?.modified()?);
+    }
+    Ok(modified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    /// sha256 of "hello world"
+    const HELLO: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    fn read_all(id: &ID) -> Vec<u8> {
+        let mut content = Vec::new();
+        id.open().unwrap().read_to_end(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn compressed_files_are_stored_and_read_back() {
+        let dir = TempDir::new("cas").unwrap();
+        let cas = LocalStorage::new(dir.path().to_path_buf())
+            .with_compression(Compression::Zstd { level: 3 });
+
+        let stored = cas.store_from(&mut &b"hello world"[..]).unwrap();
+        assert_eq!(stored.id(), HELLO);
+        let path = dir.path().join("b9/4d").join(format!("{}.zst", HELLO));
+        assert_eq!(stored.path(), Some(path.as_path()));
+
+        let found = cas.str_to_id(HELLO).unwrap().unwrap();
+        assert_eq!(found.path(), Some(path.as_path()));
+        assert_eq!(read_all(&found), b"hello world");
+        assert_eq!(found.content_length().unwrap(), 11);
+        // Nothing is left behind in scratch space
+        assert_eq!(read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn uncompressed_files_are_stored_as_they_are() {
+        let dir = TempDir::new("cas").unwrap();
+        let cas = LocalStorage::new(dir.path().to_path_buf());
+
+        let stored = cas.store_from(&mut &b"hello world"[..]).unwrap();
+        let path = dir.path().join("b9/4d").join(HELLO);
+        assert_eq!(stored.path(), Some(path.as_path()));
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+        assert_eq!(stored.content_length().unwrap(), 11);
+    }
+
+    #[test]
+    fn missing_and_malformed_ids_arent_found() {
+        let dir = TempDir::new("cas").unwrap();
+        let cas = LocalStorage::new(dir.path().to_path_buf());
+        assert!(cas.str_to_id(HELLO).unwrap().is_none());
+        assert!(cas.str_to_id("../../etc/passwd").unwrap().is_none());
+    }
+
+    /// Store "hello world", but with `content`
+    fn corrupt(cas: &Path, content: &[u8]) -> PathBuf {
+        let dir = cas.join("b9/4d");
+        create_dir_all(&dir).unwrap();
+        let path = dir.join(HELLO);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn truncated_files_are_quarantined() {
+        let dir = TempDir::new("cas").unwrap();
+        let cas = LocalStorage::new(dir.path().to_path_buf())
+            .with_compression(Compression::Zstd { level: 3 });
+        let stored = cas.store_from(&mut &b"hello world"[..]).unwrap();
+        let path = stored.path().unwrap();
+        let length = path.metadata().unwrap().len();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_len(length - 4)
+            .unwrap();
+
+        let fsck = cas.fsck().unwrap();
+        assert_eq!((fsck.checked, fsck.errors), (1, 0));
+        let quarantined = dir.path().join("quarantine").join(format!("{}.zst", HELLO));
+        assert_eq!(fsck.quarantined, vec![quarantined.clone()]);
+        assert!(!path.exists());
+        assert!(cas.str_to_id(HELLO).unwrap().is_none());
+
+        // Quarantined twice under the same name, both are kept
+        let first = dir.path().join("quarantine").join(HELLO);
+        let second = dir.path().join("quarantine").join(format!("{}.1", HELLO));
+        corrupt(dir.path(), b"hello");
+        assert_eq!(cas.fsck().unwrap().quarantined, vec![first.clone()]);
+        corrupt(dir.path(), b"world");
+        assert_eq!(cas.fsck().unwrap().quarantined, vec![second.clone()]);
+        assert!(quarantined.exists());
+        assert_eq!(std::fs::read(first).unwrap(), b"hello");
+        assert_eq!(std::fs::read(second).unwrap(), b"world");
+    }
+
+    #[test]
+    fn only_stale_scratch_directories_are_removed() {
+        let dir = TempDir::new("cas").unwrap();
+        let cas = LocalStorage::new(dir.path().to_path_buf());
+        let stale = dir.path().join("cas-scratch.stale");
+        let fresh = dir.path().join("cas-scratch.fresh");
+        for scratch in [&stale, &fresh].iter() {
+            create_dir_all(scratch).unwrap();
+            std::fs::write(scratch.join("cas"), "partial").unwrap();
+        }
+        let two_hours_ago = SystemTime::now() - 2 * STALE_SCRATCH;
+        for path in [stale.join("cas"), stale.clone()].iter() {
+            File::open(path).unwrap().set_modified(two_hours_ago).unwrap();
+        }
+
+        let fsck = cas.fsck().unwrap();
+        assert_eq!((fsck.scratch_removed, fsck.errors), (1, 0));
+        assert!(!stale.exists());
+        assert!(fresh.join("cas").exists());
+    }
+
+    #[test]
+    fn corrupt_files_arent_found_when_verifying_reads() {
+        let dir = TempDir::new("cas").unwrap();
+        let path = corrupt(dir.path(), b"hello");
+        let cas = LocalStorage::new(dir.path().to_path_buf());
+        assert!(cas.str_to_id(HELLO).unwrap().is_some());
+
+        let verifying = cas.with_verify_reads(true);
+        assert!(verifying.str_to_id(HELLO).unwrap().is_none());
+        assert!(!path.exists());
+        assert!(dir.path().join("quarantine").join(HELLO).exists());
+    }
+
+    #[test]
+    fn flat_files_are_found_and_migrated_into_their_shard() {
+        let dir = TempDir::new("cas").unwrap();
+        let cas = LocalStorage::new(dir.path().to_path_buf());
+        let flat = dir.path().join(HELLO);
+        std::fs::write(&flat, "hello world").unwrap();
+        let found = cas.str_to_id(HELLO).unwrap().unwrap();
+        assert_eq!(found.path(), Some(flat.as_path()));
+
+        assert_eq!(cas.migrate().unwrap(), 1);
+        let sharded = dir.path().join("b9/4d").join(HELLO);
+        assert!(!flat.exists());
+        let found = cas.str_to_id(HELLO).unwrap().unwrap();
+        assert_eq!(found.path(), Some(sharded.as_path()));
+        assert_eq!(read_all(&found), b"hello world");
+
+        assert_eq!(cas.migrate().unwrap(), 0);
+        assert!(sharded.exists());
+    }
+
+    #[test]
+    fn flat_files_stored_again_in_their_shard_are_removed() {
+        let dir = TempDir::new("cas").unwrap();
+        let cas = LocalStorage::new(dir.path().to_path_buf());
+        let stored = cas.store_from(&mut &b"hello world"[..]).unwrap();
+        let flat = dir.path().join(HELLO);
+        std::fs::write(&flat, "hello world").unwrap();
+
+        assert_eq!(cas.migrate().unwrap(), 0);
+        assert!(!flat.exists());
+        assert!(stored.path().unwrap().exists());
+        assert_eq!(cas.ids().unwrap().len(), 1);
+    }
+}
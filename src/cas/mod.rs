@@ -0,0 +1,319 @@
+//! Files stored by the sha256 of their content, e.g. the NARs of
+//! builds.
+//!
+//! Files are stored on the local filesystem, see `LocalStorage`, or in
+//! an S3-compatible object store, see `S3Storage`, so builders can
+//! write their NARs to storage the report step reads from on another
+//! machine. Either way they are kept two levels deep, by the first
+//! two pairs of hex digits of their hash: `ab/cd/abcd…`.
+//!
+//! Files may be kept zstd compressed, as `<sha256>.zst`. The id is
+//! always the hash of the uncompressed content, and `ID::open` reads
+//! it back uncompressed whichever way it is stored.
+
+use sha2::{Digest, Sha256};
+
+use std::{
+    fs::{create_dir_all, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use tempdir::TempDir;
+
+mod local;
+pub use local::{Fsck, LocalStorage};
+
+mod s3;
+pub use s3::{Bucket, S3Error, S3Storage};
+
+pub trait ContentAddressedStorage: Send + Sync {
+    /// Store everything `reader` yields, named by its hash.
+    fn store_from(&self, reader: &mut dyn Read) -> Result<ID, io::Error>;
+
+    /// The stored file with this id, if there is one. Errors are
+    /// failures to look, not a missing file.
+    fn str_to_id(&self, id: &str) -> Result<Option<ID>, io::Error>;
+}
+
+/// How `store_from` stores new files
+#[derive(Clone, Copy, Debug)]
+pub enum Compression {
+    None,
+    Zstd { level: i32 },
+}
+
+/// Where builds store their NARs and the report reads them from: a
+/// local directory, or a bucket of an S3-compatible object store,
+/// `s3+https://host/bucket/prefix` or `s3+http://…`.
+#[derive(Clone, Debug)]
+pub enum Location {
+    Directory(PathBuf),
+    S3(Bucket),
+}
+
+impl Location {
+    /// `scratch` is where new files are hashed and compressed before
+    /// they are uploaded to an object store. A directory is its own
+    /// scratch space, so files can be renamed into place.
+    pub fn open(
+        &self,
+        compression: Compression,
+        verify_reads: bool,
+        scratch: &Path,
+    ) -> Result<Arc<dyn ContentAddressedStorage>, S3Error> {
+        Ok(match self {
+            Location::Directory(root) => Arc::new(
+                LocalStorage::new(root.clone())
+                    .with_compression(compression)
+                    .with_verify_reads(verify_reads),
+            ),
+            Location::S3(bucket) => Arc::new(
+                S3Storage::new(bucket.clone())?
+                    .with_compression(compression)
+                    .with_verify_reads(verify_reads)
+                    .with_scratch(scratch.to_path_buf()),
+            ),
+        })
+    }
+
+    /// The directory, for what only local storage supports, like
+    /// listing or deleting what is stored. None for object stores.
+    pub fn directory(&self) -> Option<&Path> {
+        match self {
+            Location::Directory(root) => Some(root),
+            Location::S3(_) => None,
+        }
+    }
+}
+
+impl FromStr for Location {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Location, Self::Err> {
+        if s.starts_with("s3+") {
+            s.parse().map(Location::S3)
+        } else {
+            Ok(Location::Directory(PathBuf::from(s)))
+        }
+    }
+}
+
+fn is_sha256(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Where `id` is stored, relative to the root of the storage
+fn shard(id: &str) -> String {
+    format!("{}/{}", &id[0..2], &id[2..4])
+}
+
+/// The name `id` is stored under
+fn stored_name(id: &str, compressed: bool) -> String {
+    if compressed {
+        format!("{}.zst", id)
+    } else {
+        id.to_string()
+    }
+}
+
+/// New content, hashed and written out as it will be stored
+struct Scratch {
+    id: String,
+    file: PathBuf,
+    /// Removed with everything left in it when dropped
+    _dir: TempDir,
+}
+
+/// Write everything `reader` yields to a scratch directory in `dir`,
+/// compressed if asked to, and hash it on the way.
+fn write_scratch(
+    reader: &mut dyn Read,
+    dir: &Path,
+    compression: Compression,
+) -> Result<Scratch, io::Error> {
+    let mut reader = BufReader::new(reader);
+    create_dir_all(dir)?;
+    let tempdir = TempDir::new_in(dir, "cas-scratch")?;
+    let tempfile = tempdir.path().join("cas");
+
+    let mut digest = Sha256::new();
+    debug!("writing CAS to {:?}", &tempfile);
+    let mut f: Box<dyn Write> = match compression {
+        Compression::None => Box::new(BufWriter::new(File::create(&tempfile)?)),
+        Compression::Zstd { level } => Box::new(
+            zstd::Encoder::new(BufWriter::new(File::create(&tempfile)?), level)?.auto_finish(),
+        ),
+    };
+
+    let mut buf = [0; 4096];
+    loop {
+        // loop duped from std::io::copy
+        let len = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        digest.input(&buf[..len]);
+        f.write_all(&buf[..len])?;
+    }
+
+    f.flush()?;
+    drop(f);
+
+    Ok(Scratch {
+        id: format!("{:x}", digest.result()),
+        file: tempfile,
+        _dir: tempdir,
+    })
+}
+
+struct DigestWriter<'a>(&'a mut Sha256);
+impl<'a> Write for DigestWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.input(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct ID {
+    id: String,
+    stored: Stored,
+}
+
+enum Stored {
+    File(PathBuf),
+    Object {
+        storage: S3Storage,
+        key: String,
+        /// Of the object as stored, if the store told us
+        size: Option<u64>,
+    },
+}
+
+impl ID {
+    fn in_file(id: String, path: PathBuf) -> ID {
+        ID {
+            id,
+            stored: Stored::File(path),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The file as it is stored, which may be compressed, if it is
+    /// stored on the local filesystem
+    pub fn path(&self) -> Option<&Path> {
+        match self.stored {
+            Stored::File(ref path) => Some(path),
+            Stored::Object { .. } => None,
+        }
+    }
+
+    fn is_compressed(&self) -> bool {
+        match self.stored {
+            Stored::File(ref path) => path.extension().is_some_and(|extension| extension == "zst"),
+            Stored::Object { ref key, .. } => key.ends_with(".zst"),
+        }
+    }
+
+    /// Whether the content still hashes to the id. Compressed content
+    /// which can't be decompressed doesn't.
+    pub fn verify(&self) -> Result<bool, io::Error> {
+        let mut digest = Sha256::new();
+        let copied = io::copy(&mut self.open()?, &mut DigestWriter(&mut digest));
+        match copied {
+            Ok(_) => Ok(format!("{:x}", digest.result()) == self.id),
+            Err(_) if self.is_compressed() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read the uncompressed content.
+    pub fn open(&self) -> io::Result<Box<dyn Read + Send>> {
+        let stored: Box<dyn Read + Send> = match self.stored {
+            Stored::File(ref path) => Box::new(File::open(path)?),
+            Stored::Object {
+                ref storage,
+                ref key,
+                ..
+            } => storage.get(key)?,
+        };
+        if self.is_compressed() {
+            Ok(Box::new(zstd::Decoder::new(stored)?))
+        } else {
+            Ok(Box::new(BufReader::new(stored)))
+        }
+    }
+
+    /// The length of the uncompressed content. Compressed content is
+    /// read through to count it.
+    pub fn content_length(&self) -> io::Result<u64> {
+        match self.stored {
+            _ if self.is_compressed() => io::copy(&mut self.open()?, &mut io::sink()),
+            Stored::File(ref path) => Ok(path.metadata()?.len()),
+            Stored::Object { size: Some(size), .. } => Ok(size),
+            Stored::Object { size: None, .. } => io::copy(&mut self.open()?, &mut io::sink()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// sha256 of "hello world"
+    const HELLO: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    fn read_all(id: &ID) -> Vec<u8> {
+        let mut content = Vec::new();
+        id.open().unwrap().read_to_end(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn compressed_scratch_files_are_named_by_their_content() {
+        let dir = TempDir::new("cas").unwrap();
+        let scratch = write_scratch(
+            &mut &b"hello world"[..],
+            dir.path(),
+            Compression::Zstd { level: 3 },
+        )
+        .unwrap();
+        assert_eq!(scratch.id, HELLO);
+        assert_ne!(std::fs::read(&scratch.file).unwrap(), b"hello world");
+
+        let compressed = dir.path().join(stored_name(HELLO, true));
+        std::fs::rename(&scratch.file, &compressed).unwrap();
+        let id = ID::in_file(scratch.id.clone(), compressed);
+        assert!(id.is_compressed());
+        assert_eq!(read_all(&id), b"hello world");
+        assert_eq!(id.content_length().unwrap(), 11);
+        assert!(id.verify().unwrap());
+    }
+
+    #[test]
+    fn files_which_dont_decompress_dont_verify() {
+        let dir = TempDir::new("cas").unwrap();
+        let path = dir.path().join(stored_name(HELLO, true));
+        std::fs::write(&path, b"hello world").unwrap();
+        assert!(!ID::in_file(HELLO.to_string(), path).verify().unwrap());
+    }
+
+    #[test]
+    fn ids_are_sharded_by_their_first_hex_digits() {
+        assert!(is_sha256(HELLO));
+        assert!(!is_sha256("../etc/passwd"));
+        assert!(!is_sha256(&HELLO.to_uppercase()));
+        assert_eq!(shard(HELLO), "b9/4d");
+    }
+}
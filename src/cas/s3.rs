@@ -0,0 +1,523 @@
+//! Files stored in a bucket of an S3-compatible object store, like
+//! AWS S3 or MinIO, so builders and the report don't need to share a
+//! disk.
+//!
+//! Objects are named like the files of `LocalStorage`, after the
+//! prefix: `<prefix>/ab/cd/abcd…`. Requests use path-style URLs and
+//! are signed with AWS Signature Version 4, with the credentials in
+//! `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` and the region in
+//! `AWS_REGION`, us-east-1 if it isn't set.
+//!
+//! Objects can't be quarantined. With verified reads, one which
+//! doesn't match its id is treated as missing.
+//!
+//! Without the s3:ListBucket permission, looking up a missing object
+//! is forbidden rather than not found. `store_from` uploads it anyway,
+//! and `str_to_id` only fails if it is forbidden to look both ways the
+//! id may be stored.
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use super::{
+    is_sha256, shard, stored_name, write_scratch, Compression, ContentAddressedStorage, Stored,
+    ID,
+};
+
+use std::{
+    env,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+/// The body isn't part of the signature, so it can be streamed
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+/// Where objects are stored, parsed from `s3+https://host/bucket/prefix`
+/// or `s3+http://…`
+#[derive(Clone, Debug)]
+pub struct Bucket {
+    /// `http` or `https`
+    scheme: &'static str,
+    /// With the port, if one was given
+    host: String,
+    name: String,
+    /// Every key starts with this, empty or ending in `/`
+    prefix: String,
+}
+
+impl FromStr for Bucket {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Bucket, Self::Err> {
+        let (scheme, rest) = if let Some(rest) = s.strip_prefix("s3+https://") {
+            ("https", rest)
+        } else if let Some(rest) = s.strip_prefix("s3+http://") {
+            ("http", rest)
+        } else {
+            return Err("S3 storage must be s3+https://host/bucket/prefix or s3+http://…");
+        };
+
+        let mut comp = rest.splitn(3, '/');
+        let host = comp.next().unwrap_or_default();
+        let name = comp.next().unwrap_or_default();
+        let prefix = comp.next().unwrap_or_default().trim_matches('/');
+        if host.is_empty() || host.contains(['@', '?', '#']) {
+            return Err("S3 storage needs a host, e.g. s3+https://s3.amazonaws.com/bucket");
+        }
+        if name.is_empty() {
+            return Err("S3 storage needs a bucket, e.g. s3+https://s3.amazonaws.com/bucket");
+        }
+        // Keys are used in URLs and signatures as they are
+        let is_plain = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '/');
+        if !name.chars().chain(prefix.chars()).all(is_plain) {
+            return Err("S3 bucket and prefix may only contain letters, digits, '.', '_', '-' and '/'");
+        }
+
+        Ok(Bucket {
+            scheme,
+            host: host.to_string(),
+            name: name.to_string(),
+            prefix: if prefix.is_empty() {
+                String::new()
+            } else {
+                format!("{}/", prefix)
+            },
+        })
+    }
+}
+
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    region: String,
+}
+
+impl Credentials {
+    fn from_env() -> Result<Credentials, S3Error> {
+        let var = |name| env::var(name).map_err(|_| S3Error::MissingCredentials(name));
+        Ok(Credentials {
+            access_key_id: var("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY")?,
+            region: env::var("AWS_REGION").unwrap_or_else(|_| String::from("us-east-1")),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct S3Storage {
+    bucket: Arc<Bucket>,
+    credentials: Arc<Credentials>,
+    agent: ureq::Agent,
+    compression: Compression,
+    verify_reads: bool,
+    /// Where new files are hashed and compressed before they are
+    /// uploaded
+    scratch: PathBuf,
+}
+
+impl S3Storage {
+    pub fn new(bucket: Bucket) -> Result<S3Storage, S3Error> {
+        Ok(S3Storage {
+            bucket: Arc::new(bucket),
+            credentials: Arc::new(Credentials::from_env()?),
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(30))
+                .timeout_read(Duration::from_secs(300))
+                .build(),
+            compression: Compression::None,
+            verify_reads: false,
+            scratch: PathBuf::from("./tmp/"),
+        })
+    }
+
+    /// Re-hash objects when they are looked up by `str_to_id`, and
+    /// ignore them if they don't match.
+    pub fn with_verify_reads(self, verify_reads: bool) -> S3Storage {
+        S3Storage {
+            verify_reads,
+            ..self
+        }
+    }
+
+    pub fn with_compression(self, compression: Compression) -> S3Storage {
+        S3Storage {
+            compression,
+            ..self
+        }
+    }
+
+    /// Hash and compress new files in `scratch` before uploading them,
+    /// rather than in ./tmp/
+    pub fn with_scratch(self, scratch: PathBuf) -> S3Storage {
+        S3Storage { scratch, ..self }
+    }
+
+    fn key(&self, id: &str, compressed: bool) -> String {
+        format!("{}{}/{}", self.bucket.prefix, shard(id), stored_name(id, compressed))
+    }
+
+    /// None if there is no such object, or else its size if the store
+    /// said. Fails with `PermissionDenied` if looking is forbidden.
+    fn head(&self, key: &str) -> Result<Option<Option<u64>>, io::Error> {
+        match self.request("HEAD", key).call() {
+            Ok(response) => Ok(Some(
+                response
+                    .header("Content-Length")
+                    .and_then(|length| length.parse().ok()),
+            )),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e @ ureq::Error::Status(403, _)) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                e.to_string(),
+            )),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+
+    pub(super) fn get(&self, key: &str) -> Result<Box<dyn Read + Send>, io::Error> {
+        let response = self.request("GET", key).call().map_err(io_error)?;
+        Ok(Box::new(response.into_reader()))
+    }
+
+    fn put(&self, key: &str, path: &Path, size: u64) -> Result<(), io::Error> {
+        // S3 doesn't take chunked uploads, so the length is given
+        self.request("PUT", key)
+            .set("Content-Length", &size.to_string())
+            .send(File::open(path)?)
+            .map_err(io_error)?;
+        Ok(())
+    }
+
+    /// A request for `key`, signed with AWS Signature Version 4.
+    fn request(&self, method: &str, key: &str) -> ureq::Request {
+        let Bucket {
+            scheme,
+            ref host,
+            ref name,
+            ..
+        } = *self.bucket;
+        let credentials = &self.credentials;
+        let now = Utc::now();
+        let date = now.format("%Y%m%d").to_string();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let path = format!("/{}/{}", name, key);
+
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, host, UNSIGNED_PAYLOAD, timestamp, SIGNED_HEADERS, UNSIGNED_PAYLOAD
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, credentials.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+            timestamp,
+            scope,
+            Sha256::digest(canonical_request.as_bytes())
+        );
+
+        let mut signing_key = hmac(
+            format!("AWS4{}", credentials.secret_access_key).as_bytes(),
+            date.as_bytes(),
+        );
+        for part in [credentials.region.as_str(), "s3", "aws4_request"].iter() {
+            signing_key = hmac(&signing_key, part.as_bytes());
+        }
+        let signature = hex::encode(hmac(&signing_key, string_to_sign.as_bytes()));
+
+        self.agent
+            .request(method, &format!("{}://{}{}", scheme, host, path))
+            // What was signed, ureq would leave out a default port
+            .set("Host", host)
+            .set("x-amz-content-sha256", UNSIGNED_PAYLOAD)
+            .set("x-amz-date", &timestamp)
+            .set(
+                "Authorization",
+                &format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    credentials.access_key_id, scope, SIGNED_HEADERS, signature
+                ),
+            )
+    }
+}
+
+impl ContentAddressedStorage for S3Storage {
+    fn store_from(&self, reader: &mut dyn Read) -> Result<ID, io::Error> {
+        let scratch = write_scratch(reader, &self.scratch, self.compression)?;
+        let compressed = matches!(self.compression, Compression::Zstd { .. });
+        let key = self.key(&scratch.id, compressed);
+        let size = scratch.file.metadata()?.len();
+
+        // Another builder may have stored the same content already
+        let stored = match self.head(&key) {
+            Ok(found) => found.is_some(),
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => false,
+            Err(e) => return Err(e),
+        };
+        if !stored {
+            debug!("Uploading {} to {}", scratch.id, key);
+            self.put(&key, &scratch.file, size)?;
+        }
+
+        Ok(ID {
+            id: scratch.id,
+            stored: Stored::Object {
+                storage: self.clone(),
+                key,
+                size: Some(size),
+            },
+        })
+    }
+
+    fn str_to_id(&self, id: &str) -> Result<Option<ID>, io::Error> {
+        if !is_sha256(id) {
+            return Ok(None);
+        }
+
+        let mut denied = None;
+        for compressed in [false, true].iter() {
+            let key = self.key(id, *compressed);
+            let size = match self.head(&key) {
+                Ok(Some(size)) => size,
+                Ok(None) => continue,
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                    denied = Some(e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let found = ID {
+                id: id.to_string(),
+                stored: Stored::Object {
+                    storage: self.clone(),
+                    key: key.clone(),
+                    size,
+                },
+            };
+            if !self.verify_reads {
+                return Ok(Some(found));
+            }
+
+            if found.verify()? {
+                return Ok(Some(found));
+            }
+            warn!("{} doesn't match its id, ignoring it", key);
+        }
+        match denied {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any length");
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+fn io_error(e: ureq::Error) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+#[derive(Debug)]
+pub enum S3Error {
+    /// The environment variable which isn't set
+    MissingCredentials(&'static str),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cas::Location;
+
+    use std::{
+        collections::HashMap,
+        fs,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Mutex,
+        },
+        thread,
+    };
+
+    use tempdir::TempDir;
+    use tiny_http::{Method, Response, Server};
+
+    /// A stand-in for MinIO, keeping objects in memory by path
+    struct FakeS3 {
+        address: String,
+        objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        puts: Arc<AtomicUsize>,
+        /// Answer everything with 500
+        failing: Arc<AtomicBool>,
+    }
+
+    impl FakeS3 {
+        /// `missing` is the status of a missing object: 404, or 403 as
+        /// for a user without s3:ListBucket
+        fn start(missing: u16) -> FakeS3 {
+            let server = Server::http("127.0.0.1:0").unwrap();
+            let fake = FakeS3 {
+                address: server.server_addr().to_ip().unwrap().to_string(),
+                objects: Arc::new(Mutex::new(HashMap::new())),
+                puts: Arc::new(AtomicUsize::new(0)),
+                failing: Arc::new(AtomicBool::new(false)),
+            };
+
+            let objects = fake.objects.clone();
+            let puts = fake.puts.clone();
+            let failing = fake.failing.clone();
+            thread::spawn(move || {
+                for mut request in server.incoming_requests() {
+                    let signed = request.headers().iter().any(|header| {
+                        header.field.equiv("Authorization")
+                            && header
+                                .value
+                                .as_str()
+                                .starts_with("AWS4-HMAC-SHA256 Credential=AKID/")
+                    });
+                    let path = request.url().to_string();
+                    let response = if failing.load(Ordering::SeqCst) {
+                        Response::from_data(vec![]).with_status_code(500)
+                    } else if !signed {
+                        Response::from_data(vec![]).with_status_code(403)
+                    } else if *request.method() == Method::Put {
+                        let mut body = Vec::new();
+                        request.as_reader().read_to_end(&mut body).unwrap();
+                        objects.lock().unwrap().insert(path, body);
+                        puts.fetch_add(1, Ordering::SeqCst);
+                        Response::from_data(vec![])
+                    } else {
+                        match objects.lock().unwrap().get(&path) {
+                            Some(object) => Response::from_data(object.clone()),
+                            None => Response::from_data(vec![]).with_status_code(missing),
+                        }
+                    };
+                    let _ = request.respond(response);
+                }
+            });
+            fake
+        }
+
+        fn storage(&self, scratch: &Path) -> S3Storage {
+            S3Storage {
+                bucket: Arc::new(
+                    format!("s3+http://{}/bucket/nars", self.address)
+                        .parse()
+                        .unwrap(),
+                ),
+                credentials: Arc::new(Credentials {
+                    access_key_id: "AKID".to_string(),
+                    secret_access_key: "secret".to_string(),
+                    region: "us-east-1".to_string(),
+                }),
+                agent: ureq::agent(),
+                compression: Compression::None,
+                verify_reads: false,
+                scratch: scratch.to_path_buf(),
+            }
+        }
+
+        fn keys(&self) -> Vec<String> {
+            let mut keys: Vec<String> = self.objects.lock().unwrap().keys().cloned().collect();
+            keys.sort();
+            keys
+        }
+    }
+
+    fn read(id: &ID) -> Vec<u8> {
+        let mut content = Vec::new();
+        id.open().unwrap().read_to_end(&mut content).unwrap();
+        content
+    }
+
+    const HELLO: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn stored_objects_are_found_and_read_back() {
+        let fake = FakeS3::start(404);
+        let scratch = TempDir::new("scratch").unwrap();
+        let storage = fake.storage(scratch.path());
+
+        let id = storage.store_from(&mut &b"hello"[..]).unwrap();
+        assert_eq!(id.id(), HELLO);
+        assert_eq!(fake.keys(), vec![format!("/bucket/nars/2c/f2/{}", HELLO)]);
+
+        // Stored content isn't uploaded again
+        storage.store_from(&mut &b"hello"[..]).unwrap();
+        assert_eq!(fake.puts.load(Ordering::SeqCst), 1);
+
+        let found = storage.str_to_id(HELLO).unwrap().unwrap();
+        assert_eq!(read(&found), b"hello");
+        assert_eq!(found.content_length().unwrap(), 5);
+        assert!(storage.str_to_id(&"0".repeat(64)).unwrap().is_none());
+
+        // Compressed objects are found whichever way new ones are stored
+        let compressed = storage.clone().with_compression(Compression::Zstd { level: 3 });
+        let id = compressed.store_from(&mut &b"hello world"[..]).unwrap();
+        assert!(fake.keys().iter().any(|key| key.ends_with(".zst")));
+        let found = storage.str_to_id(id.id()).unwrap().unwrap();
+        assert_eq!(read(&found), b"hello world");
+        assert_eq!(found.content_length().unwrap(), 11);
+    }
+
+    #[test]
+    fn new_files_are_hashed_in_the_scratch_directory() {
+        let fake = FakeS3::start(404);
+        let dir = TempDir::new("scratch").unwrap();
+        let scratch = dir.path().join("scratch");
+        fake.storage(&scratch).store_from(&mut &b"hello"[..]).unwrap();
+
+        // And the scratch space is removed once uploaded
+        assert!(scratch.is_dir());
+        assert_eq!(fs::read_dir(&scratch).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn forbidden_lookups_without_list_bucket_count_as_missing_when_storing() {
+        let fake = FakeS3::start(403);
+        let scratch = TempDir::new("scratch").unwrap();
+        let storage = fake.storage(scratch.path());
+
+        storage.store_from(&mut &b"hello"[..]).unwrap();
+        assert_eq!(fake.puts.load(Ordering::SeqCst), 1);
+
+        // The uncompressed object is found, and the compressed one
+        // after a forbidden look for the uncompressed one
+        assert_eq!(read(&storage.str_to_id(HELLO).unwrap().unwrap()), b"hello");
+        let compressed = storage.clone().with_compression(Compression::Zstd { level: 3 });
+        let id = compressed.store_from(&mut &b"hello world"[..]).unwrap();
+        assert_eq!(read(&storage.str_to_id(id.id()).unwrap().unwrap()), b"hello world");
+
+        // Missing can't be told apart from forbidden
+        let error = storage.str_to_id(&"0".repeat(64)).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn failed_lookups_are_errors_not_missing_objects() {
+        let fake = FakeS3::start(404);
+        let scratch = TempDir::new("scratch").unwrap();
+        let storage = fake.storage(scratch.path());
+        storage.store_from(&mut &b"hello"[..]).unwrap();
+
+        fake.failing.store(true, Ordering::SeqCst);
+        assert!(storage.str_to_id(HELLO).is_err());
+        assert!(storage.str_to_id(&"0".repeat(64)).is_err());
+        assert!(storage.store_from(&mut &b"hello"[..]).is_err());
+    }
+
+    #[test]
+    fn only_directories_can_be_listed() {
+        let bucket: Location = "s3+http://127.0.0.1:9000/bucket/nars".parse().unwrap();
+        assert_eq!(bucket.directory(), None);
+        let directory: Location = "./tmp/".parse().unwrap();
+        assert_eq!(directory.directory(), Some(Path::new("./tmp/")));
+    }
+}
@@ -1,269 +1,549 @@
 use log::{debug, info, warn};
 
+use chrono::{DateTime, Utc};
+
+mod shutdown;
+use shutdown::{RunningBuilds, Signals};
+
+mod variations;
+use variations::Variations;
+
 mod workqueue;
 use workqueue::WorkQueue;
 
 use crate::{
     cas::ContentAddressedStorage,
+    database::Database,
     derivation::Derivation,
-    eval::{eval, JobInstantiation},
-    messages::{BuildRequest, BuildResponseV1, BuildStatus, Hashes},
+    eval::{eval, EvalError, JobInstantiation},
+    messages::{BuildRequest, BuildResponse, BuildResponseV2, BuildStatus, Hashes, Variation},
     store::Store,
 };
 
 use std::{
-    fs::{self, File},
-    io::Write,
-    path::PathBuf,
+    collections::{HashMap, HashSet},
+    fs,
+    os::unix::{fs::MetadataExt, process::ExitStatusExt},
+    path::{Path, PathBuf},
     process::{Command, Stdio},
-    sync::mpsc::channel,
+    sync::{
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
 enum MoreToDo {
     RetryLonger,
-    CaptureCheckDir
 }
 
-fn check_reproducibility(thread_id: u16, gc_root_a: &PathBuf, drv: &PathBuf, cores: u16, timeout: Option<usize>) -> Result<BuildStatus,MoreToDo> {
-    let first_build = Command::new("nix-store")
-        .arg("--add-root")
-        .arg(&gc_root_a)
-        .arg("--indirect")
-        .arg("--realise")
-        .arg(&drv)
-        .arg("--cores")
-        .arg(format!("{}", cores))
-        .stdin(Stdio::null())
-        .output()
-        .expect("failed to execute process");
-
-    debug!(
-        "First build of {:?} exited with {:?}",
-        &drv,
-        first_build.status.code()
-    );
-
-    if !first_build.status.success() {
-        info!(
-            "(thread-{}) First build of {:?} failed. Result:\n#{:#?}",
-            thread_id, &drv, first_build
+/// What one builder thread needs to check a derivation
+struct Worker {
+    thread_id: u16,
+    builds: RunningBuilds,
+    store: Store,
+    cas: Arc<dyn ContentAddressedStorage>,
+    gc_root_a: PathBuf,
+    gc_root_check: PathBuf,
+    cores: u16,
+    rounds: u32,
+    variations: Variations,
+}
+
+impl Worker {
+    /// Check `drv`, returning the result and the variations which
+    /// were applied to every --check build.
+    fn check_reproducibility(&self, drv: &PathBuf, timeout: Option<u64>) -> Result<(BuildStatus, Vec<Variation>),MoreToDo> {
+        let thread_id = self.thread_id;
+        let first_build = self.builds.run(Command::new("nix-store")
+            .arg("--add-root")
+            .arg(&self.gc_root_a)
+            .arg("--indirect")
+            .arg("--realise")
+            .arg(drv)
+            .arg("--cores")
+            .arg(format!("{}", self.cores))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()))
+            .expect("failed to execute process");
+
+        debug!(
+            "First build of {:?} exited with {:?}",
+            &drv,
+            first_build.status.code()
         );
 
-        return Ok(BuildStatus::FirstFailed);
+        if !first_build.status.success() {
+            info!(
+                "(thread-{}) First build of {:?} failed. Result:\n#{:#?}",
+                thread_id, &drv, first_build
+            );
+
+            return Ok((BuildStatus::FirstFailed, vec![]));
+        }
+
+        let mut hashes: Hashes = Hashes::new();
+        let mut disagreed = 0;
+        let mut applied: Option<Vec<Variation>> = None;
+
+        for round in 1..=self.rounds {
+            debug!(
+                "(thread-{}) Performing --check build {} of {}: {:#?}",
+                thread_id, round, self.rounds, drv
+            );
+            // Nix only replaces a .check directory when a new round
+            // disagrees, so one may be left from before this round
+            let left_over = check_dirs(drv);
+            let mut prepared = self.variations.prepare(self.cores);
+            let mut command = Command::new("nix-store");
+            command.arg("--realise").arg(drv);
+            prepared.apply(&mut command);
+            let second_build = self.builds.run(command
+                .arg("--timeout")
+                .arg(format!("{}", timeout.unwrap_or(0)))
+                .arg("--check")
+                .arg("--keep-failed")
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null()))
+                .expect("failed to execute process")
+                .status;
+            debug!(
+                "Second build of {:?} exited with {:?}",
+                &drv,
+                second_build.code()
+            );
+            prepared.confirm();
+            let applied = applied.get_or_insert_with(|| prepared.applied.clone());
+            applied.retain(|variation| prepared.applied.contains(variation));
+            drop(prepared);
+
+            if second_build.success() {
+                debug!("(thread-{}) Round {} agreed: {:?}", thread_id, round, drv);
+            } else if second_build.signal().is_some() {
+                info!("(thread-{}) --check build was killed: {:?}", thread_id, drv);
+                return Ok((BuildStatus::SecondFailed, vec![]));
+            } else if second_build.code() == Some(101) {
+                info!("(thread-{}) Needs more time: {:?}", thread_id, drv);
+                return Err(MoreToDo::RetryLonger);
+            } else if self.calc(drv, &mut hashes, &left_over) {
+                debug!("(thread-{}) Round {} disagreed: {:?}", thread_id, round, drv);
+                disagreed += 1;
+            } else {
+                return Ok((BuildStatus::SecondFailed, applied.clone()));
+            }
+        }
+
+        let status = if disagreed == 0 {
+            info!("(thread-{}) Reproducible: {:?}", thread_id, drv);
+            BuildStatus::Reproducible
+        } else if disagreed == self.rounds {
+            info!("(thread-{}) Unreproducible: {:?}", thread_id, drv);
+            BuildStatus::Unreproducible(hashes)
+        } else {
+            info!(
+                "(thread-{}) Flaky, {} of {} rounds disagreed: {:?}",
+                thread_id, disagreed, self.rounds, drv
+            );
+            BuildStatus::Flaky {
+                rounds: self.rounds,
+                disagreed,
+                hashes,
+            }
+        };
+        Ok((status, applied.unwrap_or_default()))
     }
 
-    debug!(
-        "(thread-{}) Performing --check build: {:#?}",
-        thread_id, drv
-    );
-    let second_build = Command::new("nix-store")
-        .arg("--realise")
-        .arg(&drv)
-        .arg("--cores")
-        .arg(format!("{}", cores))
-        .arg("--timeout")
-        .arg(format!("{}", timeout.unwrap_or(0)))
-        .arg("--check")
-        .arg("--keep-failed")
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()
-        .expect("failed to execute process")
-        .wait()
-        .expect("failed to wait");
-    debug!(
-        "Second build of {:?} exited with {:?}",
-        &drv,
-        second_build.code()
-    );
-
-    if second_build.success() {
-        info!("(thread-{}) Reproducible: {:?}", thread_id, drv);
-        return Ok(BuildStatus::Reproducible);
-    } else if second_build.code() == Some(101) {
-        info!("(thread-{}) Needs more time: {:?}", thread_id, drv);
-        return Err(MoreToDo::RetryLonger);
-    } else {
-        info!("(thread-{}) Unreproducible: {:?}", thread_id, drv);
-        return Err(MoreToDo::CaptureCheckDir);
+    /// Add the hashes of the outputs a failed --check left behind to
+    /// `hashes`, ignoring the .check directories in `left_over` which
+    /// were there before it. Returns false if it left nothing behind.
+    fn calc(&self, drv: &Path, hashes: &mut Hashes, left_over: &HashMap<PathBuf, CheckDir>) -> bool {
+        let parsed_drv = Derivation::parse(drv).unwrap();
+        let (store, cas) = (&self.store, &self.cas);
+
+        // For each output, look for a .check directory.
+        // If we find one, we want to:
+        //
+        // 1. add it to the store right away -- .check directories
+        //    aren't actually store paths and cannot be saved from
+        //    being garbage collected
+        //
+        // 2. create a GC root for what we just added to the store
+        //    see: https://github.com/NixOS/nix/issues/2676
+        //
+        // 3. create a NAR for the .check store path
+        //
+        // 4. create a NAR for the output store path, unless an earlier
+        //    round already did
+        //
+        // 5. hash the NARs
+        //
+        // 6. add the hashes we haven't seen yet
+        let mut found = false;
+
+        for (output, path) in parsed_drv.outputs().iter() {
+            let check_path = check_path(path);
+
+            debug!("Looking for {:?}", check_path);
+
+            let check_dir = CheckDir::of(&check_path);
+            if check_dir.is_some() && check_dir.as_ref() == left_over.get(&check_path) {
+                debug!("Ignoring {:?}, an earlier build left it", check_path);
+            } else if check_dir.is_some() {
+                debug!("Found {:?}", check_path);
+                found = true;
+                let checked =
+                    store.add_path(&check_path, &self.gc_root_check).unwrap();
+
+                let output_hashes = hashes.entry(output.to_string()).or_default();
+                if output_hashes.is_empty() {
+                    let (mut path_stream, path_wait) =
+                        store.export_nar(path).unwrap();
+                    output_hashes.push(cas.store_from(&mut path_stream).unwrap().into());
+                    path_wait.wait().unwrap();
+                }
+
+                let (mut checked_stream, checked_wait) =
+                    store.export_nar(&checked).unwrap();
+                let checked_hash = cas.store_from(&mut checked_stream).unwrap().into();
+                checked_wait.wait().unwrap();
+                if !output_hashes.contains(&checked_hash) {
+                    output_hashes.push(checked_hash);
+                }
+
+                println!("{:#?}", hashes);
+            } else {
+                debug!("Did not find {:?}", check_path);
+            }
+        }
+
+        found
     }
 }
 
-fn calc(drv: &PathBuf, store: &Store, gc_root_check: &PathBuf, cas: &ContentAddressedStorage) -> BuildStatus {
-    let parsed_drv = Derivation::parse(&drv).unwrap();
-
-    // For each output, look for a .check directory.
-    // If we find one, we want to:
-    //
-    // 1. add it to the store right away -- .check directories
-    //    aren't actually store paths and cannot be saved from
-    //    being garbage collected
-    //
-    // 2. create a GC root for what we just added to the store
-    //    see: https://github.com/NixOS/nix/issues/2676
-    //
-    // 3. create a NAR for the .check store path
-    //
-    // 4. create a NAR for the output store path
-    //
-    // 5. hash the two NARs
-    //
-    // 6. return a build result with the two hashes
-    let mut hashes: Hashes = Hashes::new();
-
-    for (output, path) in parsed_drv.outputs().iter() {
-        // with_extension, naively, will replace foo-1.2.3 with foo-1.2.check
-        let mut check_name = path
-            .file_name()
-            .expect("should have a file name")
-            .to_owned();
-        check_name.push(".check");
-        let check_path = path.with_file_name(check_name);
-
-        debug!("Looking for {:?}", check_path);
-
-        if check_path.exists() {
-            debug!("Found {:?}", check_path);
-            let checked =
-                store.add_path(&check_path, &gc_root_check).unwrap();
-
-            let (path_stream, mut path_wait) =
-                store.export_nar(&path).unwrap();
-            let (checked_stream, mut checked_wait) =
-                store.export_nar(&checked).unwrap();
-
-            hashes.insert(
-                output.to_string(),
-                (
-                    cas.from_read(path_stream).unwrap().into(),
-                    cas.from_read(checked_stream).unwrap().into(),
-                ),
-            );
+/// Where a failed --check leaves its build of the output at `path`
+fn check_path(path: &Path) -> PathBuf {
+    // with_extension, naively, will replace foo-1.2.3 with foo-1.2.check
+    let mut check_name = path
+        .file_name()
+        .expect("should have a file name")
+        .to_owned();
+    check_name.push(".check");
+    path.with_file_name(check_name)
+}
 
-            println!("{:#?}", hashes);
+/// A .check directory as it was at some point. Nix moves each new one
+/// into place, which gives it a new inode and change time.
+#[derive(PartialEq, Debug)]
+struct CheckDir {
+    ino: u64,
+    ctime: i64,
+    ctime_nsec: i64,
+}
 
-            path_wait.wait().unwrap();
-            checked_wait.wait().unwrap();
-        } else {
-            debug!("Did not find {:?}", check_path);
-        }
+impl CheckDir {
+    fn of(path: &Path) -> Option<CheckDir> {
+        let metadata = fs::symlink_metadata(path).ok()?;
+        Some(CheckDir {
+            ino: metadata.ino(),
+            ctime: metadata.ctime(),
+            ctime_nsec: metadata.ctime_nsec(),
+        })
     }
+}
+
+/// The .check directories of `drv`'s outputs which exist right now
+fn check_dirs(drv: &Path) -> HashMap<PathBuf, CheckDir> {
+    let parsed_drv = Derivation::parse(drv).unwrap();
+    parsed_drv
+        .outputs()
+        .values()
+        .map(|path| check_path(path))
+        .filter_map(|path| CheckDir::of(&path).map(|dir| (path, dir)))
+        .collect()
+}
 
-    if hashes.is_empty() {
-        BuildStatus::SecondFailed
-    } else {
-        BuildStatus::Unreproducible(hashes)
+#[derive(Clone)]
+struct Builder {
+    request: BuildRequest,
+    result_tx: Sender<BuildResponseV2>,
+    cas: Arc<dyn ContentAddressedStorage>,
+    tmpdir: PathBuf,
+    cores: u16,
+    rounds: u32,
+    deadline: Option<DateTime<Utc>>,
+    builds: RunningBuilds,
+}
+
+impl Builder {
+    /// Start a thread checking everything from `queues`, one after the
+    /// other. Builds which exceed `timeout` go to `slow_queue`, or are
+    /// reported as `TimedOut` if there isn't one.
+    fn spawn(&self, thread_id: u16, queues: Vec<WorkQueue>, timeout: Option<u64>, mut slow_queue: Option<WorkQueue>) -> thread::JoinHandle<()> {
+        info!("Starting thread {}", thread_id);
+
+        let Builder { request, result_tx, cas, tmpdir, cores, rounds, deadline, builds } = self.clone();
+        let mut tmpdir = tmpdir;
+        tmpdir.push(format!("thread-{}", thread_id));
+        fs::create_dir_all(&tmpdir).unwrap();
+
+        let mut gc_root_a = tmpdir.clone();
+        gc_root_a.push("buildA");
+
+        let mut gc_root_check = tmpdir.clone();
+        gc_root_check.push("check");
+
+        let worker = Worker {
+            thread_id,
+            builds,
+            store: Store::new(),
+            cas,
+            gc_root_a,
+            gc_root_check,
+            cores,
+            rounds,
+            variations: Variations::new(request.variations(), tmpdir.join("disorderfs")),
+        };
+
+        thread::Builder::new()
+            .name(format!("builder-{}", thread_id))
+            .spawn(move || {
+                'queues: for mut queue in queues {
+                    while let Some(drv) = queue.next() {
+                        if deadline.is_some_and(|deadline| Utc::now() > deadline) {
+                            warn!("(thread-{}) The request's deadline has passed, stopping", thread_id);
+                            queue.complete(&drv);
+                            break 'queues;
+                        }
+
+                        info!("(thread-{}) Checking: {:#?}", thread_id, drv);
+                        let result = match worker.check_reproducibility(&drv, timeout) {
+                            Ok(result) => Some(result),
+                            Err(MoreToDo::RetryLonger) => match slow_queue {
+                                Some(ref mut slow_queue) => {
+                                    // Its outputs are built, so what depends
+                                    // on it doesn't have to wait
+                                    slow_queue.push(drv.clone());
+                                    None
+                                }
+                                None => {
+                                    warn!("(thread-{}) Ran out of time again: {:?}", thread_id, drv);
+                                    Some((BuildStatus::TimedOut, vec![]))
+                                }
+                            },
+                        };
+                        queue.complete(&drv);
+
+                        if let Some((status, variations)) = result {
+                            result_tx.send(BuildResponseV2 {
+                                request: request.clone(),
+                                drv: drv.to_str().unwrap().to_string(),
+                                status,
+                                variations,
+                            }).unwrap();
+                        }
+                    }
+                }
+
+                debug!("no more work, shutting down {}", thread_id);
+            })
+            .unwrap()
     }
 }
 
-pub fn check(instruction: BuildRequest, maximum_cores: u16, maximum_cores_per_job: u16) {
-    let job = match instruction {
-        BuildRequest::V1(ref req) => req.clone(),
-    };
+/// Map each derivation in `to_build` to its input derivations, so
+/// they can be checked inputs first.
+fn input_graph(to_build: HashSet<PathBuf>) -> HashMap<PathBuf, Vec<PathBuf>> {
+    to_build
+        .into_iter()
+        .map(|drv| {
+            let inputs = match Derivation::parse(&drv) {
+                Ok(parsed) => parsed.input_drvs().cloned().collect(),
+                Err(e) => {
+                    warn!("Failed to parse {:?}, building it in any order: {:?}", drv, e);
+                    vec![]
+                }
+            };
+            (drv, inputs)
+        })
+        .collect()
+}
+
+pub struct CheckOptions {
+    pub maximum_cores: u16,
+    pub maximum_cores_per_job: u16,
+    /// Time limit in seconds for the first pass of --check builds.
+    /// Builds which exceed it are retried after everything else.
+    pub timeout: Option<u64>,
+    /// Time limit in seconds for the retry of builds which exceeded
+    /// `timeout`, or None for no limit.
+    pub slow_timeout: Option<u64>,
+    /// How many times to --check each build
+    pub rounds: u32,
+    /// Results database, see `database`
+    pub database: PathBuf,
+    /// Where the logs of older versions are imported from
+    pub legacy_logs: PathBuf,
+    /// How long running builds may finish after SIGINT or SIGTERM
+    /// before they are killed
+    pub grace_period: Duration,
+    /// Where the NARs of builds are stored, and read back from to be
+    /// uploaded
+    pub cas: Arc<dyn ContentAddressedStorage>,
+    /// A local Nixpkgs checkout to evaluate instead of fetching the
+    /// request's revision, which then only names the results
+    pub nixpkgs_path: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum CheckError {
+    Eval(EvalError),
+    /// Stopped by SIGINT or SIGTERM, what's left is checked next time
+    Interrupted,
+}
+
+impl From<EvalError> for CheckError {
+    fn from(e: EvalError) -> Self {
+        CheckError::Eval(e)
+    }
+}
+
+/// Build and check every derivation of `instruction`, writing the
+/// results to the results database. `on_result` is called with
+/// each final result as soon as it is known, and with each
+/// evaluation failure if the request can't be instantiated.
+///
+/// On SIGINT or SIGTERM no more builds are started, and the running
+/// ones get `options.grace_period` to finish, or until the next
+/// signal. Builds which fail after that aren't recorded, they are
+/// retried next time.
+pub fn check<F>(instruction: BuildRequest, options: &CheckOptions, mut on_result: F) -> Result<(), CheckError>
+where
+    F: FnMut(&BuildResponse),
+{
+    let deadline = instruction.deadline();
 
     let (result_tx, result_rx) = channel();
     let tmpdir = PathBuf::from("./tmp/");
+    let mut database = Database::open(&options.database, &options.legacy_logs).expect("Unable to open the results database");
 
     let JobInstantiation {
-        mut to_build, mut results, skip_list, ..
-    } = eval(instruction.clone());
+        mut to_build, skip_list, ..
+    } = match eval(instruction.clone(), options.nixpkgs_path.as_deref(), &mut database) {
+        Ok(instantiation) => instantiation,
+        Err(e) => {
+            for failure in e.failures.iter() {
+                on_result(&BuildResponse::EvaluationFailureV1(failure.clone()));
+            }
+            return Err(e.into());
+        }
+    };
 
     // Remove builds that have succeeded before, by holding onto everything not on the skip list
     to_build.retain(|drv| !skip_list.contains(drv));
     let to_build_len = to_build.len();
 
-    let mut queue: WorkQueue = WorkQueue::new(to_build.into_iter().collect());
-
-    let cas = ContentAddressedStorage::new(tmpdir.clone());
+    let mut queue: WorkQueue = WorkQueue::with_inputs(input_graph(to_build));
+    let slow_queue = WorkQueue::new(vec![]);
+    let builds = RunningBuilds::default();
+    let signals = Signals::install();
+
+    let builder = Builder {
+        request: instruction.clone(),
+        result_tx,
+        cas: options.cas.clone(),
+        tmpdir,
+        cores: options.maximum_cores_per_job,
+        rounds: options.rounds,
+        deadline,
+        builds: builds.clone(),
+    };
 
     // In the future, only give 1 core to jobs which don't allow
     // parallel builds
-    let timeout_seconds = None;
-    let slow_queue: WorkQueue = WorkQueue::new(vec![]);
-    let thread_count = maximum_cores / maximum_cores_per_job;
-    info!("Starting {} threads", thread_count);
-    let threads: Vec<thread::JoinHandle<()>> = ((0 + 1)..=thread_count)
-        .map(|thread_id| {
-            info!("Starting thread {}", thread_id);
-
-            let result_tx = result_tx.clone();
-            let queue = queue.clone();
-            let mut slow_queue = slow_queue.clone();
-            let mut tmpdir = tmpdir.clone();
-            tmpdir.push(format!("thread-{}", thread_id));
-
-            let request = instruction.clone();
-            fs::create_dir_all(&tmpdir).unwrap();
-
-            let mut gc_root_a = tmpdir.clone();
-            gc_root_a.push("buildA");
-
-            let mut gc_root_check = tmpdir.clone();
-            gc_root_check.push("check");
-            let cas = cas.clone();
-
-            thread::Builder::new()
-                .name(format!("builder-{}", thread_id))
-                .spawn(move || {
-                    let store = Store::new();
-
-                    for drv in queue {
-                        info!("(thread-{}) Checking: {:#?}", thread_id, drv);
-                        match check_reproducibility(thread_id, &gc_root_a, &drv, maximum_cores_per_job, timeout_seconds) {
-                            Ok(status) => {
-                                result_tx.send(BuildResponseV1 {
-                                    request: request.clone(),
-                                    drv: drv.to_str().unwrap().to_string(),
-                                    status: status,
-                                }).unwrap();
-                            }
-                            Err(MoreToDo::RetryLonger) => {
-                                slow_queue.push(drv);
-                            },
-                            Err(MoreToDo::CaptureCheckDir) => {
-                                result_tx.send(BuildResponseV1 {
-                                    request: request.clone(),
-                                    drv: drv.to_str().unwrap().to_string(),
-                                    status: calc(&drv, &store, &gc_root_check, &cas),
-                                }).unwrap();
-                            },
-                        }
-                    }
+    let thread_count = options.maximum_cores / options.maximum_cores_per_job;
+    let (timeout, slow_timeout) = (options.timeout, options.slow_timeout);
+    let scheduler = {
+        let (queue, slow_queue) = (queue.clone(), slow_queue.clone());
+        thread::Builder::new()
+            .name("scheduler".to_string())
+            .spawn(move || {
+                // First pass: everything, with the short timeout. What runs
+                // out of time is set aside for the second pass.
+                info!("Starting {} threads", thread_count);
+                let threads: Vec<thread::JoinHandle<()>> = (1..=thread_count)
+                    .map(|thread_id| {
+                        builder.spawn(thread_id, vec![queue.clone()], timeout, Some(slow_queue.clone()))
+                    })
+                    .collect();
+                for thread in threads {
+                    thread.join().unwrap();
+                }
 
-                    debug!("no more work, shutting down {}", thread_id);
-                })
-                .unwrap()
-        })
-        .collect();
-    drop(result_tx);
+                // Second pass: the slow ones, with the long timeout, and
+                // anything requeued after the first pass finished.
+                info!("{} derivations need more time", slow_queue.len());
+                let threads: Vec<thread::JoinHandle<()>> = (1..=thread_count)
+                    .map(|thread_id| {
+                        builder.spawn(thread_id, vec![slow_queue.clone(), queue.clone()], slow_timeout, None)
+                    })
+                    .collect();
+                for thread in threads {
+                    thread.join().unwrap();
+                }
+            })
+            .unwrap()
+    };
 
-    let mut i = 0;
     let mut total = 0;
 
     let mut requeues: Vec<String> = vec![];
+    let mut stopping: Option<Instant> = None;
+    let mut killed = false;
+    // Coordinators which send V1 requests can't read V2 responses
+    let mut report = |response: &BuildResponseV2| match response.clone().into_message() {
+        Some(message) => on_result(&message),
+        None => warn!(
+            "Not reporting {:?} of {}, a V1 response can't express it",
+            response.status, response.drv
+        ),
+    };
+
+    loop {
+        if stopping.is_none() && signals.received() > 0 {
+            warn!("Stopping, waiting up to {:?} for running builds", options.grace_period);
+            queue.stop();
+            slow_queue.stop();
+            stopping = Some(Instant::now());
+        }
+        if let Some(since) = stopping {
+            if !killed && (signals.received() > 1 || since.elapsed() > options.grace_period) {
+                warn!("Killing the running builds");
+                builds.kill_all();
+                killed = true;
+            }
+        }
 
-    for response in result_rx.iter() {
-        i += 1;
+        let response = match result_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(response) => response,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
         total += 1;
-        if i == 10 {
-            i = 0;
-            debug!("Writing out interim state to the reproducibility log");
-            let mut log_file = File::create(format!(
-                "reproducibility-log-{}.json",
-                &job.nixpkgs_revision
-            ))
-            .unwrap();
-            log_file
-                .write_all(serde_json::to_string(&results).unwrap().as_bytes())
-                .unwrap();
+
+        if stopping.is_some() && !matches!(response.status, BuildStatus::Reproducible | BuildStatus::Unreproducible(_) | BuildStatus::Flaky { .. }) {
+            // Likely interrupted, not a real failure
+            info!("Not recording {:?} of {} after stopping", response.status, response.drv);
+            continue;
         }
 
         if response.status == BuildStatus::FirstFailed {
             if requeues.contains(&response.drv) {
                 warn!("FirstFailed, retried, failed again: {:#?}", response);
-                results.push(response);
+                report(&response);
+                database.record(&response).expect("Unable to record the result");
                 if requeues.len() > 3 {
                     panic!("Too many builds failed first time around.");
                 }
@@ -275,21 +555,43 @@ pub fn check(instruction: BuildRequest, maximum_cores: u16, maximum_cores_per_jo
                 total -= 1;
             }
         } else {
-            results.push(response);
+            report(&response);
+            database.record(&response).expect("Unable to record the result");
             println!("{} / {}", total, to_build_len);
         }
     }
 
-    for thread in threads {
-        thread.join().unwrap();
+    scheduler.join().unwrap();
+
+    if stopping.is_some() {
+        return Err(CheckError::Interrupted);
     }
+    Ok(())
+}
 
-    let mut log_file = File::create(format!(
-        "reproducibility-log-{}.json",
-        &job.nixpkgs_revision
-    ))
-    .unwrap();
-    log_file
-        .write_all(serde_json::to_string(&results).unwrap().as_bytes())
-        .unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    #[test]
+    fn check_directories_left_by_earlier_rounds_are_told_apart() {
+        let dir = TempDir::new("check").unwrap();
+        let output = dir.path().join("00000000000000000000000000000000-hello-1.2.3");
+        let check = check_path(&output);
+        assert_eq!(check, dir.path().join("00000000000000000000000000000000-hello-1.2.3.check"));
+        assert_eq!(CheckDir::of(&check), None);
+
+        fs::create_dir(&check).unwrap();
+        let left_over = CheckDir::of(&check).unwrap();
+        assert_eq!(CheckDir::of(&check).as_ref(), Some(&left_over));
+
+        // As nix does when a later round disagrees again
+        let build = dir.path().join("build");
+        fs::create_dir(&build).unwrap();
+        fs::remove_dir(&check).unwrap();
+        fs::rename(&build, &check).unwrap();
+        assert_ne!(CheckDir::of(&check).as_ref(), Some(&left_over));
+    }
 }
@@ -0,0 +1,84 @@
+use std::{
+    collections::HashSet,
+    io,
+    os::unix::process::{CommandExt, ExitStatusExt},
+    process::{Command, ExitStatus, Output, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Counts the SIGINTs and SIGTERMs received.
+#[derive(Clone, Default)]
+pub struct Signals(Arc<AtomicUsize>);
+
+impl Signals {
+    pub fn install() -> Signals {
+        let signals = Signals::default();
+        let counter = signals.0.clone();
+        if let Err(e) = ctrlc::set_handler(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }) {
+            warn!("Unable to handle SIGINT and SIGTERM: {:?}", e);
+        }
+        signals
+    }
+
+    pub fn received(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// The nix-store processes builds are running in, each in its own
+/// process group so a signal to r13y doesn't interrupt them.
+#[derive(Clone, Default)]
+pub struct RunningBuilds {
+    process_groups: Arc<Mutex<HashSet<u32>>>,
+    killed: Arc<AtomicBool>,
+}
+
+impl RunningBuilds {
+    pub fn run(&self, command: &mut Command) -> io::Result<Output> {
+        let mut process_groups = self.process_groups.lock().unwrap();
+        if self.killed.load(Ordering::SeqCst) {
+            return Ok(killed_output());
+        }
+        let child = command.process_group(0).spawn()?;
+        let pid = child.id();
+        process_groups.insert(pid);
+        drop(process_groups);
+
+        let output = child.wait_with_output();
+        self.process_groups.lock().unwrap().remove(&pid);
+        output
+    }
+
+    /// Kill every running build, and fail every later one right away.
+    pub fn kill_all(&self) {
+        let process_groups = self.process_groups.lock().unwrap();
+        self.killed.store(true, Ordering::SeqCst);
+
+        for pid in process_groups.iter() {
+            debug!("Killing process group {}", pid);
+            let kill = Command::new("kill")
+                .arg("-TERM")
+                .arg("--")
+                .arg(format!("-{}", pid))
+                .stdin(Stdio::null())
+                .status();
+            if !kill.is_ok_and(|status| status.success()) {
+                warn!("Failed to kill process group {}", pid);
+            }
+        }
+    }
+}
+
+/// What a build killed by SIGTERM looks like
+fn killed_output() -> Output {
+    Output {
+        status: ExitStatus::from_raw(15),
+        stdout: vec![],
+        stderr: vec![],
+    }
+}
@@ -0,0 +1,209 @@
+use crate::messages::Variation;
+
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Once,
+    time::SystemTime,
+};
+
+/// Warn about disorderfs being ignored once, not for every build
+static IGNORED_BUILD_DIR: Once = Once::new();
+
+/// The variations one builder thread applies to its --check builds.
+pub struct Variations {
+    requested: Vec<Variation>,
+    /// Where this thread may mount disorderfs
+    scratch: PathBuf,
+}
+
+impl Variations {
+    pub fn new(requested: &[Variation], scratch: PathBuf) -> Variations {
+        Variations {
+            requested: requested.to_vec(),
+            scratch,
+        }
+    }
+
+    /// Set up the variations for one --check build. Anything which
+    /// can't be set up is left out, with a warning.
+    pub fn prepare(&self, cores: u16) -> Prepared {
+        let mut prepared = Prepared {
+            applied: vec![],
+            cores,
+            disorderfs: None,
+        };
+
+        for variation in self.requested.iter() {
+            match variation {
+                Variation::Cores { cores } => {
+                    prepared.cores = *cores;
+                }
+                Variation::Disorderfs => match Disorderfs::mount(&self.scratch) {
+                    Ok(disorderfs) => prepared.disorderfs = Some(disorderfs),
+                    Err(e) => {
+                        warn!("Not using disorderfs, mounting it failed: {:?}", e);
+                        continue;
+                    }
+                },
+            }
+            prepared.applied.push(variation.clone());
+        }
+
+        prepared
+    }
+}
+
+/// Variations ready for a --check build, undone when dropped.
+pub struct Prepared {
+    /// What the build will be done with. Call `confirm` once it is
+    /// done to leave out what the daemon ignored.
+    pub applied: Vec<Variation>,
+    cores: u16,
+    disorderfs: Option<Disorderfs>,
+}
+
+impl Prepared {
+    /// Add the options for a `nix-store --realise --check` command.
+    ///
+    /// The daemon only honors `build-dir` from trusted users, and
+    /// older ones don't know it, see `confirm`.
+    pub fn apply(&self, command: &mut Command) {
+        command.arg("--cores").arg(format!("{}", self.cores));
+        if let Some(ref disorderfs) = self.disorderfs {
+            command.arg("--option").arg("build-dir").arg(&disorderfs.mountpoint);
+        }
+    }
+
+    /// After the build, drop the variations it turned out not to be
+    /// done with.
+    pub fn confirm(&mut self) {
+        if let Some(ref disorderfs) = self.disorderfs {
+            if !disorderfs.was_used() {
+                IGNORED_BUILD_DIR.call_once(|| {
+                    warn!("The nix daemon ignored build-dir, so disorderfs isn't used. Is this user trusted?")
+                });
+                self.applied.retain(|variation| *variation != Variation::Disorderfs);
+            }
+        }
+    }
+}
+
+/// A disorderfs which shuffles directory entries, mounted at
+/// `scratch/mount` and backed by `scratch/backing`, unmounted when
+/// dropped.
+struct Disorderfs {
+    mountpoint: PathBuf,
+    backing: PathBuf,
+}
+
+impl Disorderfs {
+    fn mount(scratch: &Path) -> io::Result<Disorderfs> {
+        let backing = scratch.join("backing");
+        let mountpoint = scratch.join("mount");
+        fs::create_dir_all(&backing)?;
+        fs::create_dir_all(&mountpoint)?;
+        let mountpoint = fs::canonicalize(&mountpoint)?;
+
+        let mount = Command::new("disorderfs")
+            .arg("--multi-user=yes")
+            .arg("--shuffle-dirents=yes")
+            .arg(&backing)
+            .arg(&mountpoint)
+            .stdin(Stdio::null())
+            .output()?;
+        if !mount.status.success() {
+            return Err(io::Error::other(String::from_utf8_lossy(&mount.stderr).into_owned()));
+        }
+
+        let disorderfs = Disorderfs { mountpoint, backing };
+        disorderfs.reset()?;
+        Ok(disorderfs)
+    }
+
+    /// Set the backing directory's modification time far into the
+    /// past. Adding or removing an entry sets it to now.
+    fn reset(&self) -> io::Result<()> {
+        File::open(&self.backing)?.set_modified(SystemTime::UNIX_EPOCH)
+    }
+
+    /// Whether anything built in it since it was mounted. A build
+    /// creates its directory in the build-dir, and removes it or
+    /// leaves it behind, either way the backing directory changes.
+    fn was_used(&self) -> bool {
+        match fs::metadata(&self.backing).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified != SystemTime::UNIX_EPOCH,
+            Err(e) => {
+                warn!("Failed to check whether disorderfs was used: {:?}", e);
+                false
+            }
+        }
+    }
+}
+
+impl Drop for Disorderfs {
+    fn drop(&mut self) {
+        let unmount = Command::new("fusermount")
+            .arg("-u")
+            .arg(&self.mountpoint)
+            .stdin(Stdio::null())
+            .status();
+        if !unmount.is_ok_and(|status| status.success()) {
+            warn!("Failed to unmount disorderfs at {:?}", self.mountpoint);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    /// As if disorderfs was mounted over `scratch`
+    fn prepared(scratch: &Path) -> Prepared {
+        let disorderfs = Disorderfs {
+            mountpoint: scratch.join("mount"),
+            backing: scratch.join("backing"),
+        };
+        fs::create_dir_all(&disorderfs.backing).unwrap();
+        disorderfs.reset().unwrap();
+        Prepared {
+            applied: vec![Variation::Disorderfs, Variation::Cores { cores: 3 }],
+            cores: 3,
+            disorderfs: Some(disorderfs),
+        }
+    }
+
+    #[test]
+    fn disorderfs_is_passed_as_the_build_dir() {
+        let scratch = TempDir::new("variations").unwrap();
+        let prepared = prepared(scratch.path());
+        let mut command = Command::new("nix-store");
+        prepared.apply(&mut command);
+
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args[..2], ["--cores", "3"]);
+        assert_eq!(args[2..4], ["--option", "build-dir"]);
+        assert_eq!(args[4], scratch.path().join("mount"));
+    }
+
+    #[test]
+    fn disorderfs_is_only_recorded_if_the_build_used_it() {
+        let scratch = TempDir::new("variations").unwrap();
+
+        let mut ignored = prepared(scratch.path());
+        ignored.confirm();
+        assert_eq!(ignored.applied, vec![Variation::Cores { cores: 3 }]);
+
+        let mut used = prepared(scratch.path());
+        // The build's directory, created and removed by the daemon
+        let build = scratch.path().join("backing/nix-build-hello-1");
+        fs::create_dir(&build).unwrap();
+        fs::remove_dir(&build).unwrap();
+        used.confirm();
+        assert_eq!(used.applied, vec![Variation::Disorderfs, Variation::Cores { cores: 3 }]);
+    }
+}
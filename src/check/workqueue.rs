@@ -1,25 +1,112 @@
 use std::{
+    collections::{HashMap, HashSet},
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{Arc, Condvar, Mutex},
 };
 
+/// Hands out derivations only once every input derivation it was
+/// given has been `complete`d, so a --check never starts before its
+/// inputs were checked and built.
+///
+/// Iterating blocks while nothing is ready but other derivations are
+/// still being worked on, and ends once everything is done.
 #[derive(Clone)]
 pub struct WorkQueue {
-    queue: Arc<Mutex<Vec<PathBuf>>>,
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+#[derive(Default)]
+struct State {
+    ready: Vec<PathBuf>,
+    /// Derivations and how many of their inputs aren't complete yet
+    waiting: HashMap<PathBuf, usize>,
+    /// Input derivations and which waiting derivations need them
+    dependents: HashMap<PathBuf, Vec<PathBuf>>,
+    in_flight: HashSet<PathBuf>,
+    stopped: bool,
 }
 
 impl WorkQueue {
     pub fn new(to_build: Vec<PathBuf>) -> Self {
+        WorkQueue::with_inputs(to_build.into_iter().map(|drv| (drv, vec![])).collect())
+    }
+
+    /// `inputs` maps each derivation to build to its input
+    /// derivations. Inputs which aren't to be built themselves are
+    /// assumed to be done.
+    pub fn with_inputs(inputs: HashMap<PathBuf, Vec<PathBuf>>) -> Self {
+        let mut state = State::default();
+
+        for (drv, drv_inputs) in inputs.iter() {
+            let pending: HashSet<&PathBuf> = drv_inputs
+                .iter()
+                .filter(|input| inputs.contains_key(*input) && *input != drv)
+                .collect();
+
+            if pending.is_empty() {
+                state.ready.push(drv.clone());
+            } else {
+                state.waiting.insert(drv.clone(), pending.len());
+                for input in pending {
+                    state
+                        .dependents
+                        .entry(input.clone())
+                        .or_default()
+                        .push(drv.clone());
+                }
+            }
+        }
+
         Self {
-            queue: Arc::new(Mutex::new(to_build)),
+            state: Arc::new((Mutex::new(state), Condvar::new())),
         }
     }
 
+    pub fn len(&self) -> usize {
+        let state = self.state.0.lock().expect("Failed to get lock on WorkQueue");
+        state.ready.len() + state.waiting.len()
+    }
+
+    /// Add a derivation which is ready to build right away.
     pub fn push(&mut self, path: PathBuf) {
-        self.queue
-            .lock()
+        let (lock, cvar) = &*self.state;
+        lock.lock()
             .expect("Failed to get lock on WorkQueue")
+            .ready
             .push(path);
+        cvar.notify_one();
+    }
+
+    /// Stop handing out derivations, what is left stays unchecked.
+    pub fn stop(&self) {
+        let (lock, cvar) = &*self.state;
+        lock.lock().expect("Failed to get lock on WorkQueue").stopped = true;
+        cvar.notify_all();
+    }
+
+    /// Mark a derivation handed out by this queue as done, whether it
+    /// built or not, releasing what was waiting on it. Does nothing for
+    /// other derivations.
+    pub fn complete(&self, path: &PathBuf) {
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().expect("Failed to get lock on WorkQueue");
+        if !state.in_flight.remove(path) {
+            return;
+        }
+
+        for dependent in state.dependents.remove(path).unwrap_or_default() {
+            let remaining = state
+                .waiting
+                .get_mut(&dependent)
+                .expect("dependents are always waiting");
+            *remaining -= 1;
+            if *remaining == 0 {
+                state.waiting.remove(&dependent);
+                state.ready.push(dependent);
+            }
+        }
+
+        cvar.notify_all();
     }
 }
 
@@ -27,9 +114,158 @@ impl std::iter::Iterator for WorkQueue {
     type Item = PathBuf;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.queue
-            .lock()
-            .expect("Failed to get lock on WorkQueue")
-            .pop()
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().expect("Failed to get lock on WorkQueue");
+
+        loop {
+            if state.stopped {
+                return None;
+            }
+
+            if let Some(path) = state.ready.pop() {
+                state.in_flight.insert(path.clone());
+                return Some(path);
+            }
+
+            if state.in_flight.is_empty() {
+                if state.waiting.is_empty() {
+                    return None;
+                }
+
+                // Nothing will ever complete the inputs these are
+                // waiting on, a cycle shouldn't happen but don't hang.
+                warn!(
+                    "{} derivations wait on inputs which will never finish, releasing them",
+                    state.waiting.len()
+                );
+                let waiting: Vec<PathBuf> = state.waiting.drain().map(|(drv, _)| drv).collect();
+                state.dependents.clear();
+                state.ready.extend(waiting);
+                continue;
+            }
+
+            state = cvar.wait(state).expect("Failed to get lock on WorkQueue");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{sync::mpsc::channel, thread, time::Duration};
+
+    fn drv(name: &str) -> PathBuf {
+        PathBuf::from(format!("/nix/store/00000000000000000000000000000000-{}.drv", name))
+    }
+
+    fn inputs(edges: &[(&str, &[&str])]) -> HashMap<PathBuf, Vec<PathBuf>> {
+        edges
+            .iter()
+            .map(|(name, inputs)| (drv(name), inputs.iter().map(|input| drv(input)).collect()))
+            .collect()
+    }
+
+    /// The next derivation the queue hands out, or None if it is still
+    /// blocked after a while
+    fn next_within(queue: &WorkQueue, timeout: Duration) -> Option<Option<PathBuf>> {
+        let (tx, rx) = channel();
+        let mut queue = queue.clone();
+        thread::spawn(move || tx.send(queue.next()));
+        rx.recv_timeout(timeout).ok()
+    }
+
+    #[test]
+    fn dependents_wait_until_their_inputs_are_complete() {
+        let mut queue = WorkQueue::with_inputs(inputs(&[
+            ("app", &["lib", "/not/to/build"]),
+            ("lib", &[]),
+            ("other", &[]),
+        ]));
+        assert_eq!(queue.len(), 3);
+
+        let mut first: Vec<PathBuf> = vec![queue.next().unwrap(), queue.next().unwrap()];
+        first.sort();
+        assert_eq!(first, vec![drv("lib"), drv("other")]);
+
+        let (tx, rx) = channel();
+        let mut waiting = queue.clone();
+        thread::spawn(move || tx.send(waiting.next()));
+
+        // Only lib is an input of app
+        queue.complete(&drv("other"));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        queue.complete(&drv("lib"));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), Some(drv("app")));
+        queue.complete(&drv("app"));
+        assert_eq!(queue.next(), None);
+    }
+
+    #[test]
+    fn failed_inputs_still_release_their_dependents() {
+        // check completes every derivation it was handed, whatever the
+        // outcome, so the dependents are checked against what is there
+        let mut queue = WorkQueue::with_inputs(inputs(&[
+            ("app", &["lib"]),
+            ("lib", &["base"]),
+            ("base", &[]),
+        ]));
+
+        assert_eq!(queue.next(), Some(drv("base")));
+        queue.complete(&drv("base"));
+        assert_eq!(queue.next(), Some(drv("lib")));
+        // lib failed to build
+        queue.complete(&drv("lib"));
+        assert_eq!(queue.next(), Some(drv("app")));
+        queue.complete(&drv("app"));
+        assert_eq!(queue.next(), None);
+    }
+
+    #[test]
+    fn completing_what_wasnt_handed_out_does_nothing() {
+        let mut queue = WorkQueue::with_inputs(inputs(&[("app", &["lib"]), ("lib", &[])]));
+        queue.complete(&drv("lib"));
+        queue.complete(&drv("unknown"));
+
+        assert_eq!(queue.next(), Some(drv("lib")));
+        assert_eq!(next_within(&queue, Duration::from_millis(100)), None);
+    }
+
+    #[test]
+    fn derivations_waiting_on_each_other_are_released() {
+        let mut queue = WorkQueue::with_inputs(inputs(&[
+            ("a", &["b"]),
+            ("b", &["a"]),
+            ("self", &["self"]),
+        ]));
+
+        // Nothing is ready or in flight but a and b wait, so they are
+        // released rather than hanging
+        let mut handed_out = vec![];
+        while let Some(drv) = next_within(&queue, Duration::from_secs(5)).expect("the queue hung") {
+            queue.complete(&drv);
+            handed_out.push(drv);
+        }
+        handed_out.sort();
+        assert_eq!(handed_out, vec![drv("a"), drv("b"), drv("self")]);
+        assert_eq!(queue.next(), None);
+    }
+
+    #[test]
+    fn stopping_ends_the_queue() {
+        let mut queue = WorkQueue::new(vec![drv("a"), drv("b")]);
+        assert!(queue.next().is_some());
+
+        let (tx, rx) = channel();
+        let mut waiting = queue.clone();
+        thread::spawn(move || {
+            tx.send((waiting.next(), waiting.next())).unwrap();
+        });
+        // Gives the consumer a chance to take b and block
+        thread::sleep(Duration::from_millis(50));
+        queue.stop();
+        let (_, after) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(after, None);
     }
 }
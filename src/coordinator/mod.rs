@@ -0,0 +1,493 @@
+//! The coordination server.
+//!
+//! Publishes the current `Signed<BuildRequest>` at `/latest` and
+//! accepts `Signed<BuildResponse>`s to it at `/result`. Responses to
+//! any other request are refused. Every accepted response is
+//! appended, still signed, to a per-revision log in the state
+//! directory. Unreproducible responses are answered with a
+//! `Signed<BuildUploadTokens>` for the NARs we don't have yet.
+//!
+//! The upload tokens point back at this server's `/upload/<sha256>`,
+//! which takes resumable PUTs: `HEAD` reports how many bytes are
+//! already stored in the `Upload-Offset` header, and a PUT with
+//! `Content-Range: bytes <offset>-<last>/<total>` continues from there.
+//! Once all bytes are in, the NAR is only kept if it hashes to the
+//! sha256 in its URL. Issued tokens are kept in the state directory,
+//! so uploads can resume after a restart.
+
+use log::{debug, info, warn};
+
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{
+    cas::{ContentAddressedStorage, LocalStorage},
+    keys::encode_public_key,
+    messages::{
+        BuildRequest, BuildResponse, BuildResponseV2, BuildUploadTokens, BuildUploadTokensV1,
+        SignatureError, Signed,
+    },
+};
+
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// Signed responses larger than this are refused.
+const MAX_RESPONSE_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Where the hashes upload tokens were issued for are kept, one per
+/// line, in the state directory
+const ISSUED_FILE: &str = "upload-tokens.txt";
+
+pub struct CoordinatorConfig {
+    /// Address to bind, e.g. `127.0.0.1:8080`
+    pub listen: String,
+    /// URL verifiers reach this server at, used for upload URLs
+    pub public_url: String,
+    /// Key to sign the request and upload tokens with
+    pub signing_key: SigningKey,
+    /// Verifier keys we accept responses from
+    pub trusted_keys: Vec<VerifyingKey>,
+    /// Where responses and uploaded NARs are kept
+    pub state_dir: PathBuf,
+    pub threads: u16,
+    /// Uploads of NARs larger than this are refused
+    pub max_upload_bytes: u64,
+}
+
+struct Coordinator {
+    latest: String,
+    /// The published request, to compare the requests responses are
+    /// for with
+    published: serde_json::Value,
+    signing_key: SigningKey,
+    trusted_keys: Vec<VerifyingKey>,
+    public_url: String,
+    state_dir: PathBuf,
+    cas: LocalStorage,
+    log_lock: Mutex<()>,
+    /// Hashes we handed out upload tokens for, also kept in
+    /// `upload-tokens.txt`
+    issued: Mutex<HashSet<String>>,
+    /// Hashes with a PUT currently being received
+    receiving: Mutex<HashSet<String>>,
+    max_upload_bytes: u64,
+}
+
+type HttpResponse = Response<io::Cursor<Vec<u8>>>;
+
+pub fn serve(instruction: BuildRequest, config: CoordinatorConfig) -> Result<(), CoordinatorError> {
+    fs::create_dir_all(&config.state_dir)?;
+
+    let latest = serde_json::to_string(&Signed::sign(&instruction, &config.signing_key)?)?;
+    let cas = LocalStorage::new(config.state_dir.join("cas"));
+    let issued = load_issued(&config.state_dir, &cas)?;
+    if !issued.is_empty() {
+        info!("{} issued uploads are still outstanding", issued.len());
+    }
+    let coordinator = Arc::new(Coordinator {
+        latest,
+        published: serde_json::to_value(&instruction)?,
+        cas,
+        signing_key: config.signing_key,
+        trusted_keys: config.trusted_keys,
+        public_url: config.public_url.trim_end_matches('/').to_string(),
+        state_dir: config.state_dir,
+        log_lock: Mutex::new(()),
+        issued: Mutex::new(issued),
+        receiving: Mutex::new(HashSet::new()),
+        max_upload_bytes: config.max_upload_bytes,
+    });
+
+    let server = Arc::new(Server::http(&config.listen).map_err(CoordinatorError::Bind)?);
+    info!(
+        "Coordinating as {} on {}",
+        encode_public_key(&coordinator.signing_key.verifying_key()),
+        config.listen
+    );
+
+    let threads: Vec<thread::JoinHandle<()>> = (1..=config.threads)
+        .map(|thread_id| {
+            let server = server.clone();
+            let coordinator = coordinator.clone();
+
+            thread::Builder::new()
+                .name(format!("coordinator-{}", thread_id))
+                .spawn(move || {
+                    for request in server.incoming_requests() {
+                        coordinator.handle(request);
+                    }
+                })
+                .unwrap()
+        })
+        .collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    Ok(())
+}
+
+impl Coordinator {
+    fn handle(&self, mut request: Request) {
+        debug!("{} {}", request.method(), request.url());
+        let url = request.url().to_string();
+
+        let upload = url.strip_prefix("/upload/").filter(|hash| is_sha256(hash));
+
+        let response = match (request.method(), url.as_str(), upload) {
+            (Method::Get, "/latest", _) => json_response(200, self.latest.clone()),
+            (Method::Post, "/result", _) => self.accept_result(&mut request),
+            (Method::Head, _, Some(hash)) => self.upload_offset(hash),
+            (Method::Put, _, Some(hash)) => self.accept_upload(&mut request, hash),
+            (_, "/latest", _) | (_, "/result", _) | (_, _, Some(_)) => {
+                text_response(405, "method not allowed")
+            }
+            _ => text_response(404, "not found"),
+        };
+
+        if let Err(e) = request.respond(response) {
+            warn!("Failed to respond to {}: {:?}", url, e);
+        }
+    }
+
+    fn accept_result(&self, request: &mut Request) -> HttpResponse {
+        let mut body = String::new();
+        if let Err(e) = request
+            .as_reader()
+            .take(MAX_RESPONSE_BYTES)
+            .read_to_string(&mut body)
+        {
+            return text_response(400, &format!("unreadable body: {}", e));
+        }
+
+        let signed: Signed<BuildResponse> = match serde_json::from_str(&body) {
+            Ok(signed) => signed,
+            Err(e) => return text_response(400, &format!("malformed response: {}", e)),
+        };
+
+        let response = match signed.verify(&self.trusted_keys) {
+            Ok(response) => response,
+            Err(SignatureError::Json(e)) => {
+                return text_response(400, &format!("malformed response: {}", e))
+            }
+            Err(e) => {
+                warn!("Rejecting response from {}: {:?}", signed.public_key(), e);
+                return text_response(403, "untrusted signature");
+            }
+        };
+
+        // Compared as JSON values, so the order of map keys doesn't matter
+        if serde_json::to_value(response.request()).ok().as_ref() != Some(&self.published) {
+            warn!(
+                "Rejecting response from {} to a request we didn't publish",
+                signed.public_key()
+            );
+            return text_response(409, "response is not for the published request");
+        }
+
+        let revision = response.request().nixpkgs_revision().to_string();
+        if revision.is_empty()
+            || !revision
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        {
+            return text_response(400, "unusable nixpkgs revision");
+        }
+
+        match (&response, response.result()) {
+            (BuildResponse::EvaluationFailureV1(failure), _) => warn!(
+                "{} failed to evaluate {:?} {:?}:\n{}",
+                signed.public_key(),
+                failure.subset,
+                failure.attrs,
+                failure.stderr
+            ),
+            (_, Some(result)) => info!(
+                "{} reports {:?} for {}",
+                signed.public_key(),
+                result.status,
+                result.drv
+            ),
+            (_, None) => {}
+        }
+        if let Err(e) = self.record(&revision, &body) {
+            warn!("Failed to record response: {:?}", e);
+            return text_response(500, "failed to record response");
+        }
+
+        match response.result() {
+            Some(BuildResponseV2 { ref status, .. }) if status.hashes().is_some() => {
+                let tokens: BuildUploadTokensV1 = status
+                    .hashes()
+                    .into_iter()
+                    .flat_map(|hashes| hashes.values().flatten())
+                    .filter(|hash| {
+                        is_sha256(hash) && !matches!(self.cas.str_to_id(hash), Ok(Some(_)))
+                    })
+                    .map(|hash| (hash.clone(), format!("{}/upload/{}", self.public_url, hash)))
+                    .collect();
+                if let Err(e) = self.issue(tokens.keys()) {
+                    warn!("Failed to record upload tokens: {:?}", e);
+                    return text_response(500, "failed to record upload tokens");
+                }
+
+                match Signed::sign(&BuildUploadTokens::V1(tokens), &self.signing_key)
+                    .map_err(CoordinatorError::from)
+                    .and_then(|signed| Ok(serde_json::to_string(&signed)?))
+                {
+                    Ok(body) => json_response(200, body),
+                    Err(e) => {
+                        warn!("Failed to sign upload tokens: {:?}", e);
+                        text_response(500, "failed to sign upload tokens")
+                    }
+                }
+            }
+            _ => text_response(204, ""),
+        }
+    }
+
+    /// Append the response, as it was signed, to the revision's log.
+    fn record(&self, revision: &str, signed: &str) -> Result<(), io::Error> {
+        let _guard = self.log_lock.lock().expect("Failed to get lock on response log");
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.state_dir.join(format!("responses-{}.jsonl", revision)))?;
+        log.write_all(format!("{}\n", signed.trim()).as_bytes())?;
+        log.sync_data()
+    }
+
+    /// Allow uploads of `hashes`, from now on and after restarts.
+    fn issue<'a>(&self, hashes: impl Iterator<Item = &'a String>) -> Result<(), io::Error> {
+        let mut issued = self.issued.lock().expect("Failed to get lock on issued tokens");
+        let new: String = hashes
+            .filter(|hash| !issued.contains(*hash))
+            .map(|hash| format!("{}\n", hash))
+            .collect();
+        if new.is_empty() {
+            return Ok(());
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.state_dir.join(ISSUED_FILE))?;
+        file.write_all(new.as_bytes())?;
+        file.sync_data()?;
+        issued.extend(new.lines().map(str::to_string));
+        Ok(())
+    }
+
+    fn partial_upload(&self, hash: &str) -> PathBuf {
+        self.state_dir.join("uploads").join(format!("{}.partial", hash))
+    }
+
+    /// How many bytes of `hash` we already have.
+    fn received(&self, hash: &str) -> u64 {
+        match self.cas.str_to_id(hash) {
+            Ok(Some(id)) => id.content_length().unwrap_or(0),
+            _ => fs::metadata(self.partial_upload(hash))
+                .map(|meta| meta.len())
+                .unwrap_or(0),
+        }
+    }
+
+    fn upload_offset(&self, hash: &str) -> HttpResponse {
+        text_response(200, "").with_header(offset_header(self.received(hash)))
+    }
+
+    fn accept_upload(&self, request: &mut Request, hash: &str) -> HttpResponse {
+        if !self
+            .issued
+            .lock()
+            .expect("Failed to get lock on issued tokens")
+            .contains(hash)
+        {
+            return text_response(403, "no upload token was issued for this hash");
+        }
+
+        let (start, total) = match request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv("Content-Range"))
+            .map(|header| parse_content_range(header.value.as_str()))
+        {
+            Some(Some(range)) => range,
+            Some(None) => return text_response(400, "unparseable Content-Range"),
+            // The whole NAR in one body, as long as it says. Without
+            // a declared length a body could fill the disk.
+            None => match request.body_length() {
+                Some(length) => (0, length as u64),
+                None => return text_response(411, "uploads need a Content-Length or Content-Range"),
+            },
+        };
+        if total > self.max_upload_bytes {
+            return text_response(413, "upload is larger than this coordinator accepts");
+        }
+
+        if !self
+            .receiving
+            .lock()
+            .expect("Failed to get lock on uploads")
+            .insert(hash.to_string())
+        {
+            return text_response(409, "an upload of this hash is in progress");
+        }
+        let response = self.receive(request, hash, start, total);
+        self.receiving
+            .lock()
+            .expect("Failed to get lock on uploads")
+            .remove(hash);
+
+        match response {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Failed to receive upload of {}: {:?}", hash, e);
+                text_response(500, "failed to store upload")
+            }
+        }
+    }
+
+    fn receive(
+        &self,
+        request: &mut Request,
+        hash: &str,
+        start: u64,
+        total: u64,
+    ) -> Result<HttpResponse, io::Error> {
+        if self.cas.str_to_id(hash)?.is_some() {
+            return Ok(text_response(200, "already stored"));
+        }
+
+        let partial = self.partial_upload(hash);
+        fs::create_dir_all(partial.parent().unwrap())?;
+        let received = self.received(hash);
+        if start != received {
+            return Ok(text_response(409, "upload does not continue from the stored offset")
+                .with_header(offset_header(received)));
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&partial)?;
+        let copied = io::copy(&mut request.as_reader().take(total - start), &mut file);
+        if let Err(e) = copied.and_then(|_| file.sync_data()) {
+            // Don't keep bytes from a body which didn't end properly,
+            // the sender aborts that way when the content is wrong.
+            file.set_len(start)?;
+            warn!("Upload of {} was cut short: {}", hash, e);
+            return Ok(text_response(400, "upload was cut short").with_header(offset_header(start)));
+        }
+
+        let received = file.metadata()?.len();
+        if received < total {
+            return Ok(text_response(202, "upload incomplete").with_header(offset_header(received)));
+        }
+
+        match self.cas.adopt(&partial, hash) {
+            Ok(_) => {
+                info!("Received {}", hash);
+                self.issued
+                    .lock()
+                    .expect("Failed to get lock on issued tokens")
+                    .remove(hash);
+                Ok(text_response(201, ""))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                warn!("Discarding upload of {}: {}", hash, e);
+                fs::remove_file(&partial)?;
+                Ok(text_response(422, "content does not match its hash"))
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The hashes upload tokens were issued for and which haven't been
+/// received yet. The received ones are dropped from the file.
+fn load_issued(state_dir: &Path, cas: &LocalStorage) -> Result<HashSet<String>, io::Error> {
+    let path = state_dir.join(ISSUED_FILE);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e),
+    };
+
+    let issued: HashSet<String> = contents
+        .lines()
+        .filter(|hash| is_sha256(hash) && !matches!(cas.str_to_id(hash), Ok(Some(_))))
+        .map(str::to_string)
+        .collect();
+    if issued.len() < contents.lines().count() {
+        let tmp = state_dir.join(format!("{}.tmp", ISSUED_FILE));
+        let mut file = File::create(&tmp)?;
+        for hash in issued.iter() {
+            writeln!(file, "{}", hash)?;
+        }
+        file.sync_data()?;
+        fs::rename(&tmp, &path)?;
+    }
+    Ok(issued)
+}
+
+/// Parse `bytes <start>-<last>/<total>`
+fn parse_content_range(value: &str) -> Option<(u64, u64)> {
+    let range = value.trim().strip_prefix("bytes ")?;
+    let (span, total) = range.split_at(range.find('/')?);
+    let total: u64 = total[1..].parse().ok()?;
+    let start: u64 = span.split('-').next()?.parse().ok()?;
+    if start > total {
+        return None;
+    }
+    Some((start, total))
+}
+
+fn offset_header(offset: u64) -> Header {
+    Header::from_bytes(&b"Upload-Offset"[..], offset.to_string().as_bytes()).unwrap()
+}
+
+fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn json_response(status: u16, body: String) -> HttpResponse {
+    Response::from_string(body)
+        .with_status_code(status)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap())
+}
+
+fn text_response(status: u16, body: &str) -> HttpResponse {
+    Response::from_string(body).with_status_code(status)
+}
+
+#[derive(Debug)]
+pub enum CoordinatorError {
+    Io(io::Error),
+    Bind(Box<dyn std::error::Error + Send + Sync>),
+    Signature(SignatureError),
+    Json(serde_json::Error),
+}
+
+impl From<io::Error> for CoordinatorError {
+    fn from(e: io::Error) -> Self {
+        CoordinatorError::Io(e)
+    }
+}
+
+impl From<SignatureError> for CoordinatorError {
+    fn from(e: SignatureError) -> Self {
+        CoordinatorError::Signature(e)
+    }
+}
+
+impl From<serde_json::Error> for CoordinatorError {
+    fn from(e: serde_json::Error) -> Self {
+        CoordinatorError::Json(e)
+    }
+}
@@ -0,0 +1,652 @@
+//! Results of every check, kept in an SQLite database.
+//!
+//! Each result is written as soon as it is known. Older versions kept
+//! them in a `reproducibility-log-<rev>.json` file per revision, which
+//! is imported the first time that revision's results are read. Its
+//! results count as recorded when the log was last written.
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::messages::{BuildResponseV2, BuildStatus};
+
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS requests (
+        id INTEGER PRIMARY KEY,
+        -- The whole request as canonical JSON
+        request TEXT NOT NULL UNIQUE,
+        nixpkgs_revision TEXT NOT NULL,
+        first_seen TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS requests_revision ON requests (nixpkgs_revision);
+
+    CREATE TABLE IF NOT EXISTS derivations (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL UNIQUE
+    );
+
+    CREATE TABLE IF NOT EXISTS results (
+        id INTEGER PRIMARY KEY,
+        request_id INTEGER NOT NULL REFERENCES requests (id),
+        derivation_id INTEGER NOT NULL REFERENCES derivations (id),
+        -- The BuildStatus variant, e.g. Reproducible
+        status TEXT NOT NULL,
+        -- The whole BuildStatus as JSON
+        detail TEXT NOT NULL,
+        recorded_at TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS results_request ON results (request_id, derivation_id);
+
+    -- The distinct NAR hashes of each output of an unreproducible or
+    -- flaky build, in the order they were built
+    CREATE TABLE IF NOT EXISTS hashes (
+        result_id INTEGER NOT NULL REFERENCES results (id),
+        output TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        sha256 TEXT NOT NULL,
+        PRIMARY KEY (result_id, output, position)
+    );
+    CREATE INDEX IF NOT EXISTS hashes_sha256 ON hashes (sha256);
+
+    CREATE TABLE IF NOT EXISTS imported_logs (
+        path TEXT PRIMARY KEY,
+        imported_at TEXT NOT NULL
+    );
+";
+
+/// Changes to `SCHEMA` made since the first version, the schema's
+/// `user_version` is how many of them were applied.
+const MIGRATIONS: &[&str] = &[
+    // The variations the --check builds were done with, as JSON
+    "ALTER TABLE results ADD COLUMN variations TEXT NOT NULL DEFAULT '[]';",
+];
+
+pub struct Database {
+    connection: Connection,
+    /// Where the logs of older versions are imported from
+    legacy_logs: PathBuf,
+}
+
+impl Database {
+    /// Open the database at `path`, importing the logs older versions
+    /// wrote to `legacy_logs` as they are needed.
+    pub fn open(path: &Path, legacy_logs: &Path) -> Result<Database, DatabaseError> {
+        let connection = Connection::open(path)?;
+        // Each result is its own transaction, appended to the
+        // write-ahead log and synced before `record` returns, so a
+        // crash or kill loses at most the result being written.
+        connection.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = FULL;
+             PRAGMA foreign_keys = ON;",
+        )?;
+        connection.busy_timeout(Duration::from_secs(30))?;
+        connection.execute_batch(SCHEMA)?;
+        migrate(&connection)?;
+        Ok(Database {
+            connection,
+            legacy_logs: legacy_logs.to_path_buf(),
+        })
+    }
+
+    /// Store a single result.
+    pub fn record(&mut self, response: &BuildResponseV2) -> Result<(), DatabaseError> {
+        let transaction = self.connection.transaction()?;
+        insert_result(&transaction, response, Utc::now())?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// The latest result of each derivation checked for `revision`,
+    /// by any request.
+    pub fn results(&mut self, revision: &str) -> Result<Vec<BuildResponseV2>, DatabaseError> {
+        self.import_legacy_log(revision)?;
+
+        let mut statement = self.connection.prepare(
+            "SELECT requests.request, derivations.path, results.detail, results.variations
+             FROM results
+             JOIN requests ON requests.id = results.request_id
+             JOIN derivations ON derivations.id = results.derivation_id
+             WHERE results.id IN (
+                 SELECT MAX(results.id)
+                 FROM results
+                 JOIN requests ON requests.id = results.request_id
+                 WHERE requests.nixpkgs_revision = ?1
+                 GROUP BY results.derivation_id
+             )
+             ORDER BY results.id",
+        )?;
+        let rows = statement.query_map(params![revision], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            let (request, drv, detail, variations) = row?;
+            results.push(BuildResponseV2 {
+                request: serde_json::from_str(&request)?,
+                drv,
+                status: serde_json::from_str(&detail)?,
+                variations: serde_json::from_str(&variations)?,
+            });
+        }
+        Ok(results)
+    }
+
+    /// The hashes of unreproducible and flaky builds recorded since
+    /// `since`, or for one of the `revisions` most recently requested
+    /// revisions. Every legacy log is imported first.
+    pub fn hashes_in_use(
+        &mut self,
+        revisions: u32,
+        since: DateTime<Utc>,
+    ) -> Result<HashSet<String>, DatabaseError> {
+        // Without the directory there are no legacy logs to import
+        let legacy_logs = match fs::read_dir(&self.legacy_logs) {
+            Ok(legacy_logs) => Some(legacy_logs),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        for entry in legacy_logs.into_iter().flatten() {
+            let name = entry?.file_name();
+            let revision = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("reproducibility-log-"))
+                .and_then(|name| name.strip_suffix(".json"));
+            if let Some(revision) = revision {
+                self.import_legacy_log(revision)?;
+            }
+        }
+
+        let mut statement = self.connection.prepare(
+            "SELECT DISTINCT hashes.sha256
+             FROM hashes
+             JOIN results ON results.id = hashes.result_id
+             JOIN requests ON requests.id = results.request_id
+             WHERE results.recorded_at >= ?1
+                OR requests.nixpkgs_revision IN (
+                    SELECT nixpkgs_revision
+                    FROM requests
+                    GROUP BY nixpkgs_revision
+                    ORDER BY MAX(first_seen) DESC
+                    LIMIT ?2
+                )",
+        )?;
+        let rows = statement.query_map(params![since.to_rfc3339(), revisions], |row| row.get(0))?;
+
+        let mut hashes = HashSet::new();
+        for row in rows {
+            hashes.insert(row?);
+        }
+        Ok(hashes)
+    }
+
+    /// Import `reproducibility-log-<revision>.json`, once.
+    fn import_legacy_log(&mut self, revision: &str) -> Result<(), DatabaseError> {
+        let name = format!("reproducibility-log-{}.json", revision);
+        let mut log_file = match File::open(self.legacy_logs.join(&name)) {
+            Ok(log_file) => log_file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let transaction = self.connection.transaction()?;
+        let imported: Option<String> = transaction
+            .query_row(
+                "SELECT imported_at FROM imported_logs WHERE path = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if imported.is_some() {
+            return Ok(());
+        }
+
+        // The log was rewritten with each result, so it was last
+        // written when its revision was last checked
+        let written = log_file
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .map(DateTime::<Utc>::from)
+            .unwrap_or_else(|_| Utc::now());
+
+        let mut contents = Vec::new();
+        log_file.read_to_end(&mut contents)?;
+        let responses = read_legacy_log(&contents, &name);
+        info!("Importing {} results from {}", responses.len(), name);
+        for response in responses.iter() {
+            insert_result(&transaction, response, written)?;
+        }
+        transaction.execute(
+            "INSERT INTO imported_logs (path, imported_at) VALUES (?1, ?2)",
+            params![name, Utc::now().to_rfc3339()],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+fn migrate(connection: &Connection) -> Result<(), DatabaseError> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Migrating the results database to version {}", i + 1);
+        connection.execute_batch(&format!(
+            "BEGIN; {} PRAGMA user_version = {}; COMMIT;",
+            migration,
+            i + 1
+        ))?;
+    }
+    Ok(())
+}
+
+/// Every complete result in a legacy log. The log was rewritten in
+/// place, so it may be cut short anywhere if that was interrupted.
+fn read_legacy_log(contents: &[u8], path: &str) -> Vec<BuildResponseV2> {
+    if let Ok(responses) = serde_json::from_slice(contents) {
+        return responses;
+    }
+
+    let mut responses = Vec::new();
+    let mut position = skip_whitespace(contents, 0);
+    if contents.get(position) != Some(&b'[') {
+        warn!("{} holds no results, ignoring it", path);
+        return responses;
+    }
+    position += 1;
+
+    loop {
+        let mut stream = serde_json::Deserializer::from_slice(&contents[position..])
+            .into_iter::<BuildResponseV2>();
+        match stream.next() {
+            Some(Ok(response)) => responses.push(response),
+            _ => break,
+        }
+        position = skip_whitespace(contents, position + stream.byte_offset());
+        if contents.get(position) != Some(&b',') {
+            break;
+        }
+        position += 1;
+    }
+
+    warn!(
+        "{} is incomplete, recovered {} results from it",
+        path,
+        responses.len()
+    );
+    responses
+}
+
+fn skip_whitespace(contents: &[u8], position: usize) -> usize {
+    position
+        + contents[position..]
+            .iter()
+            .take_while(|b| b.is_ascii_whitespace())
+            .count()
+}
+
+/// Store `response` as recorded at `recorded_at`.
+fn insert_result(
+    connection: &Connection,
+    response: &BuildResponseV2,
+    recorded_at: DateTime<Utc>,
+) -> Result<(), DatabaseError> {
+    let recorded_at = recorded_at.to_rfc3339();
+    // Through a Value, so the keys are sorted and equal requests are
+    // stored once. A legacy log imported later may have seen it first.
+    let request = serde_json::to_value(&response.request)?.to_string();
+    connection.execute(
+        "INSERT INTO requests (request, nixpkgs_revision, first_seen) VALUES (?1, ?2, ?3)
+         ON CONFLICT (request) DO UPDATE SET first_seen = MIN(first_seen, excluded.first_seen)",
+        params![request, response.request.nixpkgs_revision(), recorded_at],
+    )?;
+    let request_id: i64 = connection.query_row(
+        "SELECT id FROM requests WHERE request = ?1",
+        params![request],
+        |row| row.get(0),
+    )?;
+
+    connection.execute(
+        "INSERT OR IGNORE INTO derivations (path) VALUES (?1)",
+        params![response.drv],
+    )?;
+    let derivation_id: i64 = connection.query_row(
+        "SELECT id FROM derivations WHERE path = ?1",
+        params![response.drv],
+        |row| row.get(0),
+    )?;
+
+    connection.execute(
+        "INSERT INTO results (request_id, derivation_id, status, detail, recorded_at, variations)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            request_id,
+            derivation_id,
+            status_name(&response.status),
+            serde_json::to_string(&response.status)?,
+            recorded_at,
+            serde_json::to_string(&response.variations)?,
+        ],
+    )?;
+    let result_id = connection.last_insert_rowid();
+
+    if let Some(hashes) = response.status.hashes() {
+        for (output, output_hashes) in hashes.iter() {
+            for (position, hash) in output_hashes.iter().enumerate() {
+                connection.execute(
+                    "INSERT INTO hashes (result_id, output, position, sha256) VALUES (?1, ?2, ?3, ?4)",
+                    params![result_id, output, position as i64, hash],
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn status_name(status: &BuildStatus) -> &'static str {
+    match status {
+        BuildStatus::FirstFailed => "FirstFailed",
+        BuildStatus::SecondFailed => "SecondFailed",
+        BuildStatus::Unreproducible(_) => "Unreproducible",
+        BuildStatus::Reproducible => "Reproducible",
+        BuildStatus::TimedOut => "TimedOut",
+        BuildStatus::Flaky { .. } => "Flaky",
+    }
+}
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    Io(io::Error),
+    Sqlite(rusqlite::Error),
+    Json(serde_json::Error),
+}
+
+impl From<io::Error> for DatabaseError {
+    fn from(e: io::Error) -> Self {
+        DatabaseError::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for DatabaseError {
+    fn from(e: rusqlite::Error) -> Self {
+        DatabaseError::Sqlite(e)
+    }
+}
+
+impl From<serde_json::Error> for DatabaseError {
+    fn from(e: serde_json::Error) -> Self {
+        DatabaseError::Json(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::messages::{
+        BuildRequest, BuildRequestV1, BuildRequestV2, BuildResponseV1, BuildStatusV1, Hashes,
+        HashesV1, Subset, Variation,
+    };
+
+    use tempdir::TempDir;
+
+    /// A log as older versions wrote it: BuildResponseV1s in one JSON
+    /// array
+    fn legacy_log() -> Vec<u8> {
+        let request = BuildRequest::V1(BuildRequestV1 {
+            nixpkgs_revision: "70503758fb4b37107953dfb03ad7c0cf36ad0435".to_string(),
+            nixpkgs_sha256sum: "15g8xckhzpp84p6gv526hb6c1r286qvn8i14w8msw6172jy3kj3c".to_string(),
+            result_url: "bogus".to_string(),
+            subsets: Default::default(),
+        });
+        let mut hashes = HashesV1::new();
+        hashes.insert("out".to_string(), ("a".repeat(64), "b".repeat(64)));
+        let statuses = vec![
+            BuildStatusV1::Reproducible,
+            BuildStatusV1::Unreproducible(hashes),
+            BuildStatusV1::SecondFailed,
+        ];
+        let responses: Vec<BuildResponseV1> = statuses
+            .into_iter()
+            .enumerate()
+            .map(|(i, status)| BuildResponseV1 {
+                request: request.clone(),
+                drv: format!("/nix/store/{}-drv-{}.drv", "0".repeat(32), i),
+                status,
+            })
+            .collect();
+        serde_json::to_vec(&responses).unwrap()
+    }
+
+    fn drvs(responses: &[BuildResponseV2]) -> Vec<&str> {
+        responses.iter().map(|response| response.drv.as_str()).collect()
+    }
+
+    #[test]
+    fn complete_legacy_logs_are_read() {
+        let responses = read_legacy_log(&legacy_log(), "log");
+        assert_eq!(responses.len(), 3);
+        assert_eq!(
+            responses[1].status.hashes().unwrap()["out"],
+            vec!["a".repeat(64), "b".repeat(64)]
+        );
+    }
+
+    #[test]
+    fn results_before_the_cut_are_recovered_from_truncated_legacy_logs() {
+        let log = legacy_log();
+        let third = log
+            .windows(b"drv-2.drv".len())
+            .position(|window| window == b"drv-2.drv")
+            .unwrap();
+        let second = log
+            .windows(b"drv-1.drv".len())
+            .position(|window| window == b"drv-1.drv")
+            .unwrap();
+
+        // Cut off in the middle of the third record, inside a string
+        let responses = read_legacy_log(&log[..third], "log");
+        assert_eq!(
+            drvs(&responses),
+            vec![
+                "/nix/store/00000000000000000000000000000000-drv-0.drv",
+                "/nix/store/00000000000000000000000000000000-drv-1.drv"
+            ]
+        );
+
+        // Cut off in the middle of the second record's hashes
+        let in_hashes = second + log[second..].windows(4).position(|w| w == b"aaaa").unwrap();
+        assert_eq!(read_legacy_log(&log[..in_hashes], "log").len(), 1);
+
+        // Cut off right after the comma between two records, and
+        // before the closing bracket
+        let between = log[..third]
+            .windows(b",{".len())
+            .rposition(|window| window == b",{")
+            .unwrap();
+        assert_eq!(read_legacy_log(&log[..between + 1], "log").len(), 2);
+        assert_eq!(read_legacy_log(&log[..log.len() - 1], "log").len(), 3);
+
+        // Every possible cut recovers the records before it, in order
+        let complete = read_legacy_log(&log, "log");
+        for length in 0..log.len() {
+            let responses = read_legacy_log(&log[..length], "log");
+            assert_eq!(drvs(&responses), drvs(&complete[..responses.len()]));
+        }
+    }
+
+    #[test]
+    fn unreadable_legacy_logs_hold_no_results() {
+        assert!(read_legacy_log(b"", "log").is_empty());
+        assert!(read_legacy_log(b"{\"not\": \"a list\"}", "log").is_empty());
+        assert!(read_legacy_log(b"[{\"garbage\": 1}, ", "log").is_empty());
+    }
+
+    fn unreproducible(revision: &str, hash_a: &str, hash_b: &str) -> BuildResponseV2 {
+        let mut hashes = HashesV1::new();
+        hashes.insert("out".to_string(), (hash_a.repeat(64), hash_b.repeat(64)));
+        BuildResponseV1 {
+            request: BuildRequest::V1(BuildRequestV1 {
+                nixpkgs_revision: revision.to_string(),
+                nixpkgs_sha256sum: "bogus".to_string(),
+                result_url: "bogus".to_string(),
+                subsets: Default::default(),
+            }),
+            drv: format!("/nix/store/{}-drv.drv", "0".repeat(32)),
+            status: BuildStatusV1::Unreproducible(hashes),
+        }
+        .into()
+    }
+
+    #[test]
+    fn legacy_logs_are_imported_from_their_directory() {
+        let dir = TempDir::new("database").unwrap();
+        let logs = dir.path().join("logs");
+        fs::create_dir(&logs).unwrap();
+        fs::write(
+            logs.join("reproducibility-log-70503758fb4b37107953dfb03ad7c0cf36ad0435.json"),
+            legacy_log(),
+        )
+        .unwrap();
+
+        let mut database = Database::open(&dir.path().join("r13y.sqlite"), &logs).unwrap();
+        let results = database.results("70503758fb4b37107953dfb03ad7c0cf36ad0435").unwrap();
+        assert_eq!(results.len(), 3);
+
+        // Only once
+        let results = database.results("70503758fb4b37107953dfb03ad7c0cf36ad0435").unwrap();
+        assert_eq!(results.len(), 3);
+    }
+
+    #[test]
+    fn legacy_results_count_as_recorded_when_their_log_was_written() {
+        let dir = TempDir::new("database").unwrap();
+        let mut database = Database::open(&dir.path().join("r13y.sqlite"), dir.path()).unwrap();
+        database.record(&unreproducible("new", "c", "d")).unwrap();
+
+        // Written a year ago, but only imported now
+        let log = serde_json::to_vec(&vec![unreproducible("old", "a", "b")]).unwrap();
+        let log_path = dir.path().join("reproducibility-log-old.json");
+        fs::write(&log_path, log).unwrap();
+        let a_year_ago = Utc::now() - chrono::Duration::days(365);
+        File::options()
+            .write(true)
+            .open(&log_path)
+            .unwrap()
+            .set_modified(a_year_ago.into())
+            .unwrap();
+
+        let a_month_ago = Utc::now() - chrono::Duration::days(30);
+        let newest = database.hashes_in_use(1, a_month_ago).unwrap();
+        assert_eq!(newest, vec!["c".repeat(64), "d".repeat(64)].into_iter().collect());
+
+        let both = database.hashes_in_use(2, a_month_ago).unwrap();
+        assert_eq!(both.len(), 4);
+
+        let in_window = database.hashes_in_use(0, a_year_ago - chrono::Duration::days(1)).unwrap();
+        assert_eq!(in_window.len(), 4);
+    }
+
+    #[test]
+    fn missing_legacy_log_directories_hold_no_results() {
+        let dir = TempDir::new("database").unwrap();
+        let logs = dir.path().join("missing");
+        let mut database = Database::open(&dir.path().join("r13y.sqlite"), &logs).unwrap();
+        database.record(&unreproducible("new", "c", "d")).unwrap();
+        assert_eq!(database.hashes_in_use(1, Utc::now()).unwrap().len(), 2);
+    }
+
+    fn request_v2(revision: &str) -> BuildRequest {
+        BuildRequest::V2(BuildRequestV2 {
+            request_id: format!("{}-test", revision),
+            nixpkgs_revision: revision.to_string(),
+            nixpkgs_sha256sum: String::new(),
+            result_url: "bogus".to_string(),
+            subsets: vec![(Subset::Nixpkgs, None)].into_iter().collect(),
+            system: "x86_64-linux".to_string(),
+            deadline: None,
+            variations: vec![Variation::Disorderfs, Variation::Cores { cores: 4 }],
+            overlays: vec![],
+        })
+    }
+
+    #[test]
+    fn recorded_results_are_read_back_for_their_revision() {
+        let dir = TempDir::new("database").unwrap();
+        let path = dir.path().join("r13y.sqlite");
+        let mut hashes = Hashes::new();
+        hashes.insert(
+            "out".to_string(),
+            vec!["a".repeat(64), "b".repeat(64), "c".repeat(64)],
+        );
+        let statuses = [
+            BuildStatus::Reproducible,
+            BuildStatus::FirstFailed,
+            BuildStatus::SecondFailed,
+            BuildStatus::TimedOut,
+            BuildStatus::Unreproducible(hashes.clone()),
+            BuildStatus::Flaky {
+                rounds: 5,
+                disagreed: 2,
+                hashes,
+            },
+        ];
+        let response = |i: usize, revision: &str, status: BuildStatus| BuildResponseV2 {
+            request: request_v2(revision),
+            drv: format!("/nix/store/{}-drv-{}.drv", "0".repeat(32), i),
+            status,
+            variations: vec![Variation::Disorderfs],
+        };
+
+        let mut database = Database::open(&path, dir.path()).unwrap();
+        for (i, status) in statuses.iter().enumerate() {
+            database.record(&response(i, "rev", status.clone())).unwrap();
+        }
+        // Another revision's results aren't this one's
+        database
+            .record(&response(0, "other", BuildStatus::TimedOut))
+            .unwrap();
+        // Checked again, only the latest result counts
+        database
+            .record(&response(1, "rev", BuildStatus::Reproducible))
+            .unwrap();
+        drop(database);
+
+        let mut database = Database::open(&path, dir.path()).unwrap();
+        let results = database.results("rev").unwrap();
+        assert_eq!(results.len(), statuses.len());
+        for result in results.iter() {
+            let i = result.drv.rsplit('-').next().unwrap();
+            let i: usize = i.trim_end_matches(".drv").parse().unwrap();
+            let expected = if i == 1 {
+                BuildStatus::Reproducible
+            } else {
+                statuses[i].clone()
+            };
+            assert_eq!(result.status, expected);
+            assert_eq!(result.variations, vec![Variation::Disorderfs]);
+            assert_eq!(result.request.nixpkgs_revision(), "rev");
+            assert_eq!(result.request.variations().len(), 2);
+        }
+        assert_eq!(
+            results.last().unwrap().status,
+            BuildStatus::Reproducible,
+            "the re-check was recorded last"
+        );
+        assert_eq!(database.results("other").unwrap().len(), 1);
+        assert!(database.results("unknown").unwrap().is_empty());
+    }
+}
@@ -1,70 +1,419 @@
-use serde_json;
+//! Reads `.drv` files, which are written in the ATerm format:
+//!
+//! ```text
+//! Derive([("out","/nix/store/...-hello","","")],
+//!        [("/nix/store/...-bash.drv",["out"])],
+//!        ["/nix/store/...-builder.sh"],
+//!        "x86_64-linux","/nix/store/...-bash/bin/bash",["-e","builder.sh"],
+//!        [("name","hello"),("system","x86_64-linux")])
+//! ```
+//!
+//! Strings in derivations are bytes, and needn't be UTF-8. Paths are
+//! kept as they are, other strings are decoded lossily.
 
 use std::{
     collections::HashMap,
-    io::BufRead,
+    ffi::OsString,
+    fs,
+    os::unix::ffi::OsStringExt,
     path::{Path, PathBuf},
-    process::Command,
 };
 
-#[derive(Deserialize)]
 pub struct Derivation {
-    outputs: HashMap<String, HashMap<String, PathBuf>>,
-    // inputSrcs,
-    // inputDrvs,
-    // platform,
-    // builder,
-    // args,
-    // env: HashMap<String, String>,
+    outputs: HashMap<String, DerivationOutput>,
+    input_drvs: HashMap<PathBuf, Vec<String>>,
+    input_srcs: Vec<PathBuf>,
+    platform: String,
+    builder: String,
+    args: Vec<String>,
+    env: HashMap<String, String>,
+}
+
+pub struct DerivationOutput {
+    pub path: PathBuf,
+    /// Empty unless this is a fixed-output derivation, e.g. `r:sha256`
+    pub hash_algo: String,
+    pub hash: String,
 }
 
 impl Derivation {
     pub fn parse(drv: &Path) -> Result<Derivation, DerivationParseError> {
-        let mut drvs = Derivation::parse_many(&[drv])?;
-        let key = drv.to_str().unwrap();
-
-        match drvs.remove(key) {
-            Some(parsed) => Ok(parsed),
-            None => Err(DerivationParseError::NotInResult),
-        }
+        let contents = fs::read(drv)?;
+        Derivation::from_bytes(&contents)
     }
 
     pub fn parse_many(drvs: &[&Path]) -> Result<HashMap<String, Derivation>, DerivationParseError> {
         debug!("Parsing derivations: {:#?}", &drvs);
-        let show = Command::new("nix")
-            .arg("show-derivation")
-            .args(drvs.iter())
-            .output()?;
-        for line in show.stderr.lines() {
-            debug!("parse derivation stderr: {:?}", line);
-        }
-        Ok(serde_json::from_slice(&show.stdout)?)
+        drvs.iter()
+            .map(|drv| Ok((drv.to_str().unwrap().to_string(), Derivation::parse(drv)?)))
+            .collect()
     }
 
+    pub fn from_bytes(contents: &[u8]) -> Result<Derivation, DerivationParseError> {
+        let mut parser = Parser { input: contents, position: 0 };
+
+        parser.expect("Derive(")?;
+        let outputs = parser.list(|p| {
+            p.expect("(")?;
+            let name = p.string()?;
+            p.expect(",")?;
+            let path = p.path()?;
+            p.expect(",")?;
+            let hash_algo = p.string()?;
+            p.expect(",")?;
+            let hash = p.string()?;
+            p.expect(")")?;
+            Ok((name, DerivationOutput { path, hash_algo, hash }))
+        })?;
+        parser.expect(",")?;
+        let input_drvs = parser.list(|p| {
+            p.expect("(")?;
+            let drv = p.path()?;
+            p.expect(",")?;
+            let outputs = p.list(Parser::string)?;
+            p.expect(")")?;
+            Ok((drv, outputs))
+        })?;
+        parser.expect(",")?;
+        let input_srcs = parser.list(Parser::path)?;
+        parser.expect(",")?;
+        let platform = parser.string()?;
+        parser.expect(",")?;
+        let builder = parser.string()?;
+        parser.expect(",")?;
+        let args = parser.list(Parser::string)?;
+        parser.expect(",")?;
+        let env = parser.list(|p| {
+            p.expect("(")?;
+            let name = p.string()?;
+            p.expect(",")?;
+            let value = p.string()?;
+            p.expect(")")?;
+            Ok((name, value))
+        })?;
+        parser.expect(")")?;
+        parser.end()?;
+
+        Ok(Derivation {
+            outputs: outputs.into_iter().collect(),
+            input_drvs: input_drvs.into_iter().collect(),
+            input_srcs,
+            platform,
+            builder,
+            args,
+            env: env.into_iter().collect(),
+        })
+    }
+
+    /// The outputs whose path is known. Floating content addressed
+    /// outputs only get one once they are built.
     pub fn outputs(&self) -> HashMap<&String, &PathBuf> {
         self.outputs
             .iter()
-            .map(|(name, submap)| (name, submap.get("path")))
-            .filter_map(|(name, path)| path.map(|p| (name, p)))
+            .filter(|(_, output)| !output.path.as_os_str().is_empty())
+            .map(|(name, output)| (name, &output.path))
             .collect()
     }
+
+    pub fn output(&self, name: &str) -> Option<&DerivationOutput> {
+        self.outputs.get(name)
+    }
+
+    pub fn input_drvs(&self) -> impl Iterator<Item = &PathBuf> {
+        self.input_drvs.keys()
+    }
+
+    /// Which outputs of the input derivation `drv` are used
+    pub fn input_drv_outputs(&self, drv: &Path) -> Option<&[String]> {
+        self.input_drvs.get(drv).map(Vec::as_slice)
+    }
+
+    pub fn input_srcs(&self) -> &[PathBuf] {
+        &self.input_srcs
+    }
+
+    pub fn platform(&self) -> &str {
+        &self.platform
+    }
+
+    pub fn builder(&self) -> &str {
+        &self.builder
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn env(&self) -> &HashMap<String, String> {
+        &self.env
+    }
 }
 
-#[derive(Debug)]
-pub enum DerivationParseError {
-    Io(std::io::Error),
-    JsonDecode(serde_json::Error),
-    NotInResult,
+struct Parser<'a> {
+    input: &'a [u8],
+    position: usize,
 }
 
-impl From<serde_json::Error> for DerivationParseError {
-    fn from(e: serde_json::Error) -> Self {
-        DerivationParseError::JsonDecode(e)
+impl Parser<'_> {
+    fn error(&self, expected: &'static str) -> DerivationParseError {
+        DerivationParseError::Syntax {
+            position: self.position,
+            expected,
+        }
+    }
+
+    fn expect(&mut self, token: &'static str) -> Result<(), DerivationParseError> {
+        if self.input[self.position..].starts_with(token.as_bytes()) {
+            self.position += token.len();
+            Ok(())
+        } else {
+            Err(self.error(token))
+        }
+    }
+
+    fn end(&self) -> Result<(), DerivationParseError> {
+        if self.position == self.input.len() {
+            Ok(())
+        } else {
+            Err(self.error("end of file"))
+        }
+    }
+
+    /// `[item,item,...]`
+    fn list<T, F>(&mut self, mut item: F) -> Result<Vec<T>, DerivationParseError>
+    where
+        F: FnMut(&mut Self) -> Result<T, DerivationParseError>,
+    {
+        let mut items = Vec::new();
+        self.expect("[")?;
+        if self.expect("]").is_ok() {
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            if self.expect("]").is_ok() {
+                return Ok(items);
+            }
+            self.expect(",")?;
+        }
+    }
+
+    /// A double quoted string with `\"`, `\\`, `\n`, `\r` and `\t`
+    /// escapes.
+    fn bytes(&mut self) -> Result<Vec<u8>, DerivationParseError> {
+        self.expect("\"")?;
+        let mut bytes = Vec::new();
+        loop {
+            let rest = &self.input[self.position..];
+            // Most strings have no escapes, copy up to the next special byte at once
+            let plain = rest
+                .iter()
+                .position(|b| *b == b'"' || *b == b'\\')
+                .ok_or_else(|| self.error("\""))?;
+            bytes.extend_from_slice(&rest[..plain]);
+            self.position += plain;
+
+            if self.input[self.position] == b'"' {
+                self.position += 1;
+                break;
+            }
+
+            let escaped = *self
+                .input
+                .get(self.position + 1)
+                .ok_or_else(|| self.error("escape sequence"))?;
+            bytes.push(match escaped {
+                b'n' => b'\n',
+                b'r' => b'\r',
+                b't' => b'\t',
+                other => other,
+            });
+            self.position += 2;
+        }
+
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> Result<String, DerivationParseError> {
+        self.bytes()
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn path(&mut self) -> Result<PathBuf, DerivationParseError> {
+        self.bytes().map(|bytes| PathBuf::from(OsString::from_vec(bytes)))
     }
 }
 
+#[derive(Debug)]
+pub enum DerivationParseError {
+    Io(std::io::Error),
+    Syntax {
+        position: usize,
+        expected: &'static str,
+    },
+}
+
 impl From<std::io::Error> for DerivationParseError {
     fn from(e: std::io::Error) -> Self {
         DerivationParseError::Io(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::ffi::OsStrExt;
+
+    fn fixture(name: &str) -> Derivation {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/derivations")
+            .join(name);
+        Derivation::parse(&path).unwrap()
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn parses_a_package() {
+        let drv = fixture("hello.drv");
+        let bash = "/nix/store/hjbf9fb84dj1v80i95hwv8hnfzvpvl7n-bash-5.2-p15/bin/bash";
+
+        let outputs = drv.outputs();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(
+            outputs[&"out".to_string()],
+            Path::new("/nix/store/9wk01himfgpsaysh9xxcqyc12yb8608k-hello-2.12.1")
+        );
+        let out = drv.output("out").unwrap();
+        assert_eq!((out.hash_algo.as_str(), out.hash.as_str()), ("", ""));
+
+        let mut input_drvs: Vec<&PathBuf> = drv.input_drvs().collect();
+        input_drvs.sort();
+        assert_eq!(input_drvs.len(), 3);
+        assert_eq!(
+            drv.input_drv_outputs(input_drvs[0]),
+            Some(&["out".to_string()][..])
+        );
+        assert_eq!(
+            drv.input_srcs(),
+            &paths(&["/nix/store/378prk9kh5p31a06i5mvaj276krqyqi9-default-builder.sh"])[..]
+        );
+        assert_eq!(drv.platform(), "x86_64-linux");
+        assert_eq!(drv.builder(), bash);
+        assert_eq!(
+            drv.args(),
+            &["-e", "/nix/store/378prk9kh5p31a06i5mvaj276krqyqi9-default-builder.sh"]
+        );
+        assert_eq!(drv.env()["name"], "hello-2.12.1");
+        assert_eq!(drv.env()["doInstallCheck"], "");
+        assert_eq!(drv.env().len(), 28);
+    }
+
+    #[test]
+    fn parses_escaped_strings() {
+        let drv = fixture("write-config.drv");
+        assert_eq!(
+            drv.env()["buildCommand"],
+            "mkdir -p $out\ncat > $out/config <<'END'\n\
+             greeting = \"Hello, \\\"world\\\"\"\n\
+             path = C:\\\\Users\\\\nix\n\
+             \ttabbed\r\nEND\n"
+        );
+        assert_eq!(drv.env()["passAsFile"], "buildCommand");
+    }
+
+    #[test]
+    fn parses_several_outputs_and_inputs() {
+        let drv = fixture("openssl.drv");
+
+        let mut outputs: Vec<&String> = drv.outputs().keys().cloned().collect();
+        outputs.sort();
+        assert_eq!(outputs, vec!["bin", "debug", "dev", "doc", "man", "out"]);
+        assert_eq!(
+            drv.outputs()[&"dev".to_string()],
+            Path::new("/nix/store/bs5z973n3b5vkskghjsjid65a37h9fk6-openssl-3.0.12-dev")
+        );
+
+        assert_eq!(drv.input_drvs().count(), 6);
+        assert_eq!(
+            drv.input_drv_outputs(Path::new(
+                "/nix/store/5bzx0x1mn5ss5dm9794qjzjsczc6qk24-ncurses-6.4.drv"
+            )),
+            Some(&["dev".to_string(), "lib".to_string(), "out".to_string()][..])
+        );
+        assert_eq!(
+            drv.input_drv_outputs(Path::new(
+                "/nix/store/aycz0g4x744qbc31qdwm9wb8g4varsgk-zlib-1.3.drv"
+            )),
+            Some(&["dev".to_string(), "out".to_string()][..])
+        );
+        assert_eq!(drv.input_srcs().len(), 2);
+        assert_eq!(drv.env()["outputs"], "bin dev out man doc debug");
+    }
+
+    #[test]
+    fn parses_fixed_and_floating_outputs() {
+        let fixed = fixture("hello-src.drv");
+        let out = fixed.output("out").unwrap();
+        assert_eq!(out.hash_algo, "sha256");
+        assert_eq!(
+            out.hash,
+            "8d99142afd92576f30b0cd7cb42a8dc6809998bc5d607d88761f512e26c7db20"
+        );
+        assert!(fixed.input_srcs().is_empty());
+
+        // Not built yet, so there is no path to check
+        let floating = fixture("floating.drv");
+        assert_eq!(floating.output("out").unwrap().hash_algo, "r:sha256");
+        assert!(floating.outputs().is_empty());
+    }
+
+    #[test]
+    fn keeps_paths_which_arent_utf8() {
+        let drv = Derivation::from_bytes(
+            b"Derive([(\"out\",\"/nix/store/x-caf\xe9\",\"\",\"\")],[],[],\"x86_64-linux\",\"/bin/sh\",[],[(\"name\",\"caf\xe9\")])",
+        )
+        .unwrap();
+        let out = &drv.output("out").unwrap().path;
+        assert_eq!(out.as_os_str().as_bytes(), b"/nix/store/x-caf\xe9");
+        assert_eq!(drv.env()["name"], "caf\u{fffd}");
+    }
+
+    #[test]
+    fn rejects_malformed_derivations() {
+        let valid = fs::read(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/derivations/hello.drv"),
+        )
+        .unwrap();
+
+        // Cut short anywhere, including inside a string or an escape
+        for length in [0, 7, 30, valid.len() / 2, valid.len() - 1].iter() {
+            assert!(
+                Derivation::from_bytes(&valid[..*length]).is_err(),
+                "accepted the first {} bytes",
+                length
+            );
+        }
+
+        let mut trailing = valid.clone();
+        trailing.extend_from_slice(b"\n");
+        assert!(Derivation::from_bytes(&trailing).is_err());
+
+        for malformed in [
+            &b"Derive([(\"out\",\"/nix/store/x\",\"\")],[],[],\"\",\"\",[],[])"[..],
+            b"Derive([],[],[],\"\",\"\",[],[],)",
+            b"Derive([],[],[],\"\",\"\",[],[(\"a\",\"b\\",
+            b"derive([],[],[],\"\",\"\",[],[])",
+        ]
+        .iter()
+        {
+            match Derivation::from_bytes(malformed) {
+                Err(DerivationParseError::Syntax { .. }) => {}
+                other => panic!("expected a syntax error, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+}
@@ -1,7 +1,10 @@
-use crate::cas::ContentAddressedStorage;
+use crate::{
+    cas::{ContentAddressedStorage, LocalStorage, ID},
+    nar,
+};
 
 use std::{
-    fs::{self, create_dir_all, File},
+    fs::{self, create_dir_all},
     io,
     path::{Path, PathBuf},
     process::{Command, Stdio},
@@ -11,56 +14,38 @@ use tempdir::TempDir;
 
 #[derive(Clone)]
 pub struct Diffoscope {
-    storage: ContentAddressedStorage,
+    storage: LocalStorage,
 }
 
 impl Diffoscope {
-    pub fn new(storage: ContentAddressedStorage) -> Diffoscope {
+    pub fn new(storage: LocalStorage) -> Diffoscope {
         Diffoscope { storage }
     }
 
-    pub fn nars(&self, name: &str, path_a: &Path, path_b: &Path) -> Result<PathBuf, io::Error> {
+    pub fn nars(&self, name: &str, nar_a: &ID, nar_b: &ID) -> Result<PathBuf, io::Error> {
         assert!(!name.contains('/'));
         let tempdir = TempDir::new("diffoscope-scratch").unwrap();
         let relative_a = PathBuf::from(name).join("A");
         let relative_b = PathBuf::from(name).join("B");
 
         let dest_a = tempdir.path().join(&relative_a);
-        create_dir_all(&dest_a.parent().unwrap()).unwrap();
+        create_dir_all(dest_a.parent().unwrap()).unwrap();
         let dest_b = tempdir.path().join(&relative_b);
 
         {
-            warn!("Opening {:?}", path_a);
-            let mut open_a = File::open(path_a)?;
-            warn!("Opened {:?}", path_a);
-
-            let mut load_a = Command::new("nix-store")
-                .arg("--restore")
-                .arg(&dest_a)
-                .stdin(Stdio::piped())
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .spawn()
-                .expect("failed to execute process");
-            io::copy(&mut open_a, &mut load_a.stdin.take().unwrap()).unwrap();
-            load_a.wait().unwrap();
+            warn!("Opening {:?}", nar_a.id());
+            let open_a = nar_a.open()?;
+            warn!("Opened {:?}", nar_a.id());
+
+            nar::restore(open_a, &dest_a)?;
             fix_time(&dest_a)?;
         }
 
         {
-            warn!("Opening {:?}", path_b);
-            let mut open_b = File::open(path_b)?;
-            warn!("Opened {:?}", path_b);
-            let mut load_b = Command::new("nix-store")
-                .arg("--restore")
-                .arg(&dest_b)
-                .stdin(Stdio::piped())
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .spawn()
-                .expect("failed to execute process");
-            io::copy(&mut open_b, &mut load_b.stdin.take().unwrap()).unwrap();
-            load_b.wait().unwrap();
+            warn!("Opening {:?}", nar_b.id());
+            let open_b = nar_b.open()?;
+            warn!("Opened {:?}", nar_b.id());
+            nar::restore(open_b, &dest_b)?;
             fix_time(&dest_b)?;
         }
 
@@ -81,9 +66,11 @@ impl Diffoscope {
 
         let result = self
             .storage
-            .from_read(diff.stdout.take().unwrap())
+            .store_from(&mut diff.stdout.take().unwrap())
             .unwrap()
-            .as_path_buf();
+            .path()
+            .expect("stored locally")
+            .to_path_buf();
 
         // 1 is diff present, also internal errors
         diff.wait()?;
@@ -97,7 +84,7 @@ fn fix_time(path: &Path) -> io::Result<()> {
         .arg("--date")
         .arg("@1")
         .arg("--no-dereference")
-        .arg(&path)
+        .arg(path)
         .stdin(Stdio::null())
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
@@ -0,0 +1,37 @@
+{ flake, outputs, system, attrsJSON }:
+let
+  requested = builtins.fromJSON attrsJSON;
+
+  # flake is always locked to a commit, so this is allowed with
+  # --pure-eval
+  source = builtins.getFlake flake;
+
+  group =
+    if outputs == "packages"
+    then source.packages.${system} or { }
+    else source.nixosConfigurations or { };
+
+  # The empty attribute path stands for everything in the group.
+  attrs =
+    if requested == [ [ ] ]
+    then builtins.map (name: [ name ]) (builtins.attrNames group)
+    else requested;
+
+  select = attr:
+    if outputs == "packages"
+    then attr
+    else attr ++ [ "config" "system" "build" "toplevel" ];
+
+  attrByPath = path: set: builtins.foldl'
+    (found: name: if builtins.isAttrs found && found ? ${name} then found.${name} else null)
+    set
+    path;
+
+  tracedEval = attr:
+    let found = attrByPath (select attr) group;
+    in
+    if found != null
+    then builtins.trace "Found «${toString attr}» in ${flake}#${outputs}" found
+    else builtins.trace "Missing «${toString attr}» in ${flake}#${outputs}" null;
+
+in builtins.map tracedEval attrs
@@ -1,11 +1,38 @@
-{ revision, sha256, subfile, attrsJSON }:
+{ revision, sha256, localSrc, trackedFiles, subfile, attrsJSON, argsJSON, overlaysJSON }:
 let
   attrs = builtins.fromJSON attrsJSON;
 
-  src = builtins.fetchTarball {
-    url = "https://github.com/NixOS/nixpkgs/archive/${revision}.tar.gz";
-    inherit sha256;
-  };
+  # A local checkout is copied like fetchTarball unpacks, to a path
+  # named "source", so both give the same derivations for the same
+  # tree. Only what git tracks is copied, as trackedFiles lists it, or
+  # everything but .git if it isn't a git checkout.
+  tracked =
+    if trackedFiles != ""
+    then builtins.fromJSON (builtins.readFile (/. + trackedFiles))
+    else null;
+
+  localRoot = toString (/. + localSrc);
+
+  isCopied = path: type:
+    let
+      relative = builtins.substring
+        (builtins.stringLength localRoot + 1)
+        (builtins.stringLength path)
+        path;
+    in if tracked == null
+      then baseNameOf path != ".git"
+      else tracked ? ${relative};
+
+  src = if localSrc != ""
+    then builtins.path {
+      path = /. + localSrc;
+      name = "source";
+      filter = isCopied;
+    }
+    else builtins.fetchTarball {
+      url = "https://github.com/NixOS/nixpkgs/archive/${revision}.tar.gz";
+      inherit sha256;
+    };
 
   lib = import "${src}/lib";
 
@@ -14,12 +41,20 @@ let
   imported = import
     (builtins.trace "Importing: ${toImport}" toImport);
 
-  called = imported {
-    # Todo: pass system, once
-    # https://github.com/NixOS/nixpkgs/blob/b8d7c0cab5e5e5ea8002ce4ad38f336df12841fe/nixos/release-combined.nix#L15
-    # allows specifying the nixpkgs system. when fixed, make sure to
-    # pass --pure-eval when evaluating this file
-  };
+  # This is evaluated with --pure-eval, so the subfile is called with
+  # everything it would otherwise read from the machine: the system,
+  # and for ./default.nix an empty config and only the requested
+  # overlays.
+  args = builtins.fromJSON argsJSON;
+
+  # Each overlay is a tarball pinned by its sha256, whose default.nix
+  # is the overlay function
+  overlays = builtins.map
+    (overlay: import (builtins.fetchTarball { inherit (overlay) url sha256; }))
+    (builtins.fromJSON overlaysJSON);
+
+  called = imported
+    (if args ? overlays then args // { inherit overlays; } else args);
 
   tracedEval = attr:
     if lib.hasAttrByPath attr called
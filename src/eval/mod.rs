@@ -1,16 +1,19 @@
-use log::{debug, info};
+use log::{debug, info, warn};
 
-use crate::messages::{Attr, BuildRequest, BuildResponseV1, BuildStatus};
+use crate::{
+    database::Database,
+    messages::{Attr, BuildRequest, BuildResponseV2, BuildStatus, EvaluationFailureV1, Subset},
+};
 
 use std::{
-    collections::HashSet,
-    fs::File,
+    collections::{BTreeSet, HashMap, HashSet},
+    fs,
     io::BufRead,
     path::{Path, PathBuf},
     process::{Command, Output},
 };
 
-fn log_command_output(output: Output) {
+fn log_command_output(output: &Output) {
     for line in output.stderr.lines() {
         info!("stderr: {:?}", line)
     }
@@ -20,29 +23,200 @@ fn log_command_output(output: Output) {
     }
 }
 
-pub fn load_r13y_log(rev: &str) -> Vec<BuildResponseV1> {
-    if let Ok(log_file) = File::open(format!("reproducibility-log-{}.json", rev)) {
-        serde_json::from_reader(log_file).expect("Unable to parse r13y log")
-    } else {
-        Vec::new()
+pub struct JobInstantiation {
+    pub results: Vec<BuildResponseV2>,
+    pub to_build: HashSet<PathBuf>,
+    pub skip_list: HashSet<PathBuf>,
+    pub attribution: Attribution,
+}
+
+/// The subsets and attributes each derivation is needed by. An empty
+/// attribute path is the whole subset.
+pub type Attribution = HashMap<PathBuf, Vec<(Subset, Attr)>>;
+
+/// Every subset which failed to evaluate. Nothing is checked if any
+/// of them fail, as the set of derivations would be incomplete.
+#[derive(Debug)]
+pub struct EvalError {
+    pub failures: Vec<EvaluationFailureV1>,
+}
+
+/// Arguments to call the subset's file with. Everything the file
+/// would otherwise take from the evaluating machine is passed, so the
+/// evaluation is the same everywhere.
+fn subset_arguments(subset: &Subset, system: &str) -> serde_json::Value {
+    match subset {
+        Subset::Nixpkgs | Subset::NixpkgsWithOverlays => serde_json::json!({
+            "system": system,
+            "config": {},
+            "overlays": [],
+        }),
+        Subset::NixOSReleaseCombined => serde_json::json!({ "supportedSystems": [system] }),
+        Subset::Flake { .. } => unreachable!("flakes aren't called with arguments"),
     }
 }
 
-pub struct JobInstantiation {
-    pub results: Vec<BuildResponseV1>,
-    pub to_build: HashSet<PathBuf>,
-    pub skip_list: HashSet<PathBuf>
+/// The nix-instantiate command which evaluates `attrs` of `subset`,
+/// rooting the resulting derivations at `drv`. `tracked` is the file
+/// `write_tracked_files` wrote for a local Nixpkgs checkout.
+fn instantiate(
+    instruction: &BuildRequest,
+    subset: &Subset,
+    system: &str,
+    attrs: &[Attr],
+    drv: &Path,
+    nixpkgs_path: Option<&Path>,
+    tracked: Option<&Path>,
+) -> Command {
+    let mut eval = Command::new("nix-instantiate");
+    eval.arg("--pure-eval")
+        .arg("--add-root")
+        .arg(drv)
+        .arg("--indirect")
+        .arg("--argstr")
+        .arg("attrsJSON")
+        .arg(serde_json::to_string(attrs).unwrap());
+
+    match subset {
+        Subset::Flake { flake, outputs } => {
+            eval.args(["--extra-experimental-features", "flakes"])
+                .arg("-E")
+                .arg(include_str!("./evaluate-flake.nix"))
+                .args(["--argstr", "flake", &flake.to_string()])
+                .args(["--argstr", "outputs", &outputs.to_string()])
+                .args(["--argstr", "system", system]);
+        }
+        Subset::Nixpkgs | Subset::NixpkgsWithOverlays | Subset::NixOSReleaseCombined => {
+            let overlays = match subset {
+                Subset::NixpkgsWithOverlays => instruction.overlays(),
+                _ => &[],
+            };
+            if let Some(nixpkgs_path) = nixpkgs_path {
+                // Pure evaluation can only read paths on the search path
+                eval.arg("-I").arg(nixpkgs_path);
+            }
+            if let Some(tracked) = tracked {
+                eval.arg("-I").arg(tracked);
+            }
+            eval.arg("-E")
+                .arg(include_str!("./evaluate.nix"))
+                .args(["--argstr", "revision", instruction.nixpkgs_revision()])
+                .args(["--argstr", "sha256", instruction.nixpkgs_sha256sum()])
+                .arg("--argstr")
+                .arg("localSrc")
+                .arg(nixpkgs_path.unwrap_or_else(|| Path::new("")))
+                .arg("--argstr")
+                .arg("trackedFiles")
+                .arg(tracked.unwrap_or_else(|| Path::new("")))
+                .arg("--argstr")
+                .arg("subfile")
+                .arg(subset.file().unwrap())
+                .args(["--argstr", "argsJSON", &subset_arguments(subset, system).to_string()])
+                .args(["--argstr", "overlaysJSON", &serde_json::to_string(overlays).unwrap()]);
+        }
+    }
+    eval
 }
 
-pub fn eval(instruction: BuildRequest) -> JobInstantiation {
-    let job = match instruction {
-        BuildRequest::V1(ref req) => req.clone(),
+/// The files git tracks in the checkout at `path`, and the directories
+/// leading to them, relative to it. None if it isn't a git checkout,
+/// or only sits in one which tracks none of it.
+fn tracked_files(path: &Path) -> Option<BTreeSet<String>> {
+    let ls_files = Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .arg("ls-files")
+        .arg("-z")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+
+    let mut tracked = BTreeSet::new();
+    for file in ls_files.stdout.split(|b| *b == 0).filter(|file| !file.is_empty()) {
+        let file = String::from_utf8_lossy(file);
+        let mut end = 0;
+        while let Some(slash) = file[end..].find('/') {
+            end += slash;
+            tracked.insert(file[..end].to_string());
+            end += 1;
+        }
+        tracked.insert(file.into_owned());
+    }
+    Some(tracked).filter(|tracked| !tracked.is_empty())
+}
+
+/// Write what git tracks in the local Nixpkgs checkout to a file in
+/// `tmpdir`, for evaluate.nix to copy only that: untracked and ignored
+/// files aren't in the tarball of a commit either. None if the
+/// checkout isn't a git checkout, so all of it is copied.
+fn write_tracked_files(nixpkgs_path: &Path, tmpdir: &Path) -> Option<PathBuf> {
+    let tracked = match tracked_files(nixpkgs_path) {
+        Some(tracked) => tracked,
+        None => {
+            warn!(
+                "{:?} isn't a git checkout, evaluating all of its files",
+                nixpkgs_path
+            );
+            return None;
+        }
     };
 
+    // A JSON object, which Nix reads as an attribute set to look
+    // paths up in
+    let object: serde_json::Map<String, serde_json::Value> = tracked
+        .into_iter()
+        .map(|file| (file, serde_json::Value::Bool(true)))
+        .collect();
+    fs::create_dir_all(tmpdir).expect("Unable to create the tmp directory");
+    let path = tmpdir.join("nixpkgs-tracked-files.json");
+    fs::write(&path, serde_json::Value::Object(object).to_string())
+        .expect("Unable to write the tracked files of --nixpkgs-path");
+    // Nix only takes absolute paths
+    Some(fs::canonicalize(&path).expect("Unable to find the tracked files just written"))
+}
+
+/// A file name for `name`, which may contain e.g. the `/` of a flake
+/// reference. Distinct names give distinct file names.
+fn root_name(name: &str) -> String {
+    let mut escaped = String::new();
+    for b in name.bytes() {
+        if b.is_ascii_alphanumeric() || b"._-+".contains(&b) {
+            escaped.push(b as char);
+        } else {
+            escaped.push_str(&format!("%{:02X}", b));
+        }
+    }
+    escaped
+}
+
+/// The system of this machine, for requests which don't name one.
+fn local_system() -> Result<String, String> {
+    let query = Command::new("nix-instantiate")
+        .arg("--eval")
+        .arg("--json")
+        .arg("-E")
+        .arg("builtins.currentSystem")
+        .output()
+        .expect("failed to execute process");
+    log_command_output(&query);
+
+    if !query.status.success() {
+        return Err(String::from_utf8_lossy(&query.stderr).into_owned());
+    }
+    serde_json::from_slice(&query.stdout).map_err(|e| format!("Unexpected system: {}", e))
+}
+
+pub fn eval(
+    instruction: BuildRequest,
+    nixpkgs_path: Option<&Path>,
+    database: &mut Database,
+) -> Result<JobInstantiation, EvalError> {
     let mut results = Vec::new();
 
     let mut skip_list = HashSet::new();
-    let prev_results = load_r13y_log(&job.nixpkgs_revision);
+    let prev_results = database
+        .results(instruction.nixpkgs_revision())
+        .expect("Unable to load previous results");
     for elem in prev_results.into_iter() {
         if elem.status == BuildStatus::FirstFailed {
             info!(
@@ -58,52 +232,204 @@ pub fn eval(instruction: BuildRequest) -> JobInstantiation {
     let tmpdir = PathBuf::from("./tmp/");
 
     let mut to_build: HashSet<PathBuf> = HashSet::new();
+    let mut failures = Vec::new();
+
+    let system = match instruction.system() {
+        Some(system) => system.to_string(),
+        None => match local_system() {
+            Ok(system) => system,
+            Err(stderr) => {
+                warn!("Querying the system to evaluate for failed");
+                for (subset, requested_attrs) in instruction.subsets().iter() {
+                    failures.push(EvaluationFailureV1 {
+                        request: instruction.clone(),
+                        subset: subset.clone(),
+                        attrs: requested_attrs.clone(),
+                        stderr: stderr.clone(),
+                    });
+                }
+                return Err(EvalError { failures });
+            }
+        },
+    };
+
+    let tracked =
+        nixpkgs_path.and_then(|nixpkgs_path| write_tracked_files(nixpkgs_path, &tmpdir));
+
+    let mut attribution: Attribution = HashMap::new();
+
+    for (subset, requested_attrs) in instruction.subsets().iter() {
+        // Like the subsets' files, overlays mustn't be able to point at
+        // this machine's files
+        if *subset == Subset::NixpkgsWithOverlays {
+            if let Some(overlay) = instruction
+                .overlays()
+                .iter()
+                .find(|overlay| !overlay.url.starts_with("https://"))
+            {
+                warn!("Refusing to fetch overlay {:?}", overlay.url);
+                failures.push(EvaluationFailureV1 {
+                    request: instruction.clone(),
+                    subset: subset.clone(),
+                    attrs: requested_attrs.clone(),
+                    stderr: format!("Overlays must be fetched over https://, not {}", overlay.url),
+                });
+                continue;
+            }
+        }
+
+        // Each attribute is evaluated on its own, so its derivations
+        // get their own GC root and can be attributed to it. An empty
+        // attribute path stands for the whole subset.
+        let attrs: Vec<Attr> = requested_attrs.clone().unwrap_or_else(|| vec![vec![]]);
+        for attr in attrs.into_iter() {
+            let roots_dir = tmpdir.join("gcroots");
+            let subset_name = root_name(&subset.to_string());
+            let drv = if attr.is_empty() {
+                roots_dir.join(format!("{}.drv", subset_name))
+            } else {
+                roots_dir
+                    .join(subset_name)
+                    .join(format!("{}.drv", root_name(&attr.join("."))))
+            };
+            fs::create_dir_all(drv.parent().unwrap())
+                .expect("Unable to create the GC roots directory");
+            // For the whole subset this is the empty path, which
+            // evaluate.nix looks up as the subset itself
+            let attrs: Vec<Attr> = vec![attr.clone()];
+            let failed_attrs = Some(attrs.clone()).filter(|_| !attr.is_empty());
+
+            info!("Evaluating {:?} for {} {:#?}", subset, &system, &attrs);
+            let eval = instantiate(
+                &instruction,
+                subset,
+                &system,
+                &attrs,
+                &drv,
+                nixpkgs_path,
+                tracked.as_deref(),
+            )
+                .output()
+                .expect("failed to execute process");
+            log_command_output(&eval);
+
+            if !eval.status.success() {
+                warn!("Evaluating {:?} {:?} failed with {:?}", subset, attr, eval.status.code());
+                failures.push(EvaluationFailureV1 {
+                    request: instruction.clone(),
+                    subset: subset.clone(),
+                    attrs: failed_attrs,
+                    stderr: String::from_utf8_lossy(&eval.stderr).into_owned(),
+                });
+                continue;
+            }
+
+            // nix-instantiate prints a root for each derivation, `drv`,
+            // `drv-2` and so on
+            let roots: Vec<String> = eval.stdout.lines().map_while(Result::ok).collect();
+            if roots.is_empty() {
+                warn!("Evaluating {:?} {:?} yielded no derivations", subset, attr);
+                failures.push(EvaluationFailureV1 {
+                    request: instruction.clone(),
+                    subset: subset.clone(),
+                    attrs: failed_attrs,
+                    stderr: format!(
+                        "The evaluation yielded no derivations\n{}",
+                        String::from_utf8_lossy(&eval.stderr)
+                    ),
+                });
+                continue;
+            }
 
-    for (subset, attrs) in job.subsets.into_iter() {
-        let drv = tmpdir.join("result.drv");
-        let path: &Path = (&subset).into();
-        let attrs: Vec<Attr> = attrs.unwrap_or_default();
-
-        info!("Evaluating {:?} {:#?}", &subset, &attrs);
-        let eval = Command::new("nix-instantiate")
-            // .arg("--pure-eval") // See evaluate.nix for why this isn't passed yet
-            .arg("-E")
-            .arg(include_str!("./evaluate.nix"))
-            .arg("--add-root")
-            .arg(&drv)
-            .arg("--indirect")
-            .args(&[
-                "--argstr",
-                "revision",
-                &job.nixpkgs_revision,
-                "--argstr",
-                "sha256",
-                &job.nixpkgs_sha256sum,
-                "--argstr",
-                "subfile",
-                &path.display().to_string(),
-                "--argstr",
-                "attrsJSON",
-                &serde_json::to_string(&attrs).unwrap(),
-            ])
-            .output()
-            .expect("failed to execute process");
-        log_command_output(eval);
-
-        let query_requisites = Command::new("nix-store")
-            .arg("--query")
-            .arg("--requisites")
-            .arg(&drv)
-            .output()
-            .expect("failed to execute process");
-
-        for line in query_requisites.stdout.lines().filter_map(Result::ok) {
-            if line.ends_with(".drv") {
-                to_build.insert(line.into());
+            let query_requisites = Command::new("nix-store")
+                .arg("--query")
+                .arg("--requisites")
+                .args(&roots)
+                .output()
+                .expect("failed to execute process");
+            log_command_output(&query_requisites);
+
+            if !query_requisites.status.success() {
+                warn!("Querying the requisites of {:?} {:?} failed", subset, attr);
+                failures.push(EvaluationFailureV1 {
+                    request: instruction.clone(),
+                    subset: subset.clone(),
+                    attrs: failed_attrs,
+                    stderr: String::from_utf8_lossy(&query_requisites.stderr).into_owned(),
+                });
+                continue;
+            }
+
+            for line in query_requisites.stdout.lines().map_while(Result::ok) {
+                if line.ends_with(".drv") {
+                    let drv = PathBuf::from(line);
+                    attribution
+                        .entry(drv.clone())
+                        .or_default()
+                        .push((subset.clone(), attr.clone()));
+                    to_build.insert(drv);
+                }
             }
         }
-        log_command_output(query_requisites);
     }
 
-    JobInstantiation { to_build, results, skip_list }
+    if !failures.is_empty() {
+        return Err(EvalError { failures });
+    }
+
+    Ok(JobInstantiation {
+        to_build,
+        results,
+        skip_list,
+        attribution,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git").arg("-C").arg(dir).args(args).status().unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    #[test]
+    fn only_what_git_tracks_is_listed() {
+        let dir = TempDir::new("nixpkgs").unwrap();
+        let checkout = dir.path();
+        git(checkout, &["init", "--quiet"]);
+        fs::create_dir_all(checkout.join("pkgs/hello")).unwrap();
+        fs::write(checkout.join("pkgs/hello/default.nix"), "{ }").unwrap();
+        fs::write(checkout.join("default.nix"), "{ }").unwrap();
+        fs::write(checkout.join(".gitignore"), "result\n").unwrap();
+        git(checkout, &["add", "."]);
+
+        // Neither is in the tarball of a commit
+        fs::write(checkout.join("result"), "ignored").unwrap();
+        fs::write(checkout.join("pkgs/notes.txt"), "untracked").unwrap();
+        // Changes to tracked files are evaluated as they are
+        fs::write(checkout.join("default.nix"), "{ changed }").unwrap();
+
+        let tracked: Vec<String> = tracked_files(checkout).unwrap().into_iter().collect();
+        assert_eq!(
+            tracked,
+            vec![
+                ".gitignore",
+                "default.nix",
+                "pkgs",
+                "pkgs/hello",
+                "pkgs/hello/default.nix",
+            ]
+        );
+    }
+
+    #[test]
+    fn directories_which_arent_git_checkouts_list_nothing() {
+        let dir = TempDir::new("nixpkgs").unwrap();
+        fs::write(dir.path().join("default.nix"), "{ }").unwrap();
+        assert_eq!(tracked_files(dir.path()), None);
+    }
 }
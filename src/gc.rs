@@ -0,0 +1,260 @@
+//! Delete stored NARs and diffs no recent result refers to.
+//!
+//! A NAR is kept while the hash of an unreproducible or flaky build
+//! of one of the last few revisions, or recorded within the retention
+//! window, refers to it. Anything written within the retention window
+//! is kept too, so a check running alongside doesn't lose NARs whose
+//! results it hasn't recorded yet.
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{
+    cas::LocalStorage,
+    database::{Database, DatabaseError},
+};
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+pub struct GcOptions {
+    /// Keep what the results of this many of the most recently
+    /// requested revisions refer to
+    pub keep_revisions: u32,
+    /// Keep what results recorded within this many days refer to
+    pub keep_days: u32,
+    /// Only report what would be deleted
+    pub dry_run: bool,
+    /// Results database, see `database`
+    pub database: PathBuf,
+    /// Where the logs of older versions are imported from
+    pub legacy_logs: PathBuf,
+    /// Storage directory of the NARs of builds, see `--cas`
+    pub cas: PathBuf,
+    /// Where `report` writes, with its own copies of NARs in `cas/`
+    /// and diffoscope output in `diff/`
+    pub report_dir: PathBuf,
+}
+
+#[derive(Debug, Default)]
+pub struct Freed {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+pub fn gc(options: &GcOptions) -> Result<Freed, GcError> {
+    let since = Utc::now() - Duration::days(options.keep_days.into());
+    let mut database = Database::open(&options.database, &options.legacy_logs)?;
+    let in_use = database.hashes_in_use(options.keep_revisions, since)?;
+    info!("{} stored hashes are still in use", in_use.len());
+
+    let mut freed = Freed::default();
+
+    // Build outputs, and the copies the report diffs
+    let report_cas = options.report_dir.join("cas");
+    for root in [options.cas.as_path(), report_cas.as_path()].iter() {
+        let cas = LocalStorage::new(root.to_path_buf());
+        for id in cas.ids()? {
+            if let (false, Some(path)) = (in_use.contains(id.id()), id.path()) {
+                remove_old(path, since, options.dry_run, &mut freed)?;
+            }
+        }
+    }
+
+    // Diffs are named after the two hashes they compare
+    let diff_dir = options.report_dir.join("diff");
+    if diff_dir.exists() {
+        for entry in fs::read_dir(&diff_dir)? {
+            let path = entry?.path();
+            let hashes = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".html"))
+                .and_then(|name| name.split_once('-'));
+            if let Some((hash_a, hash_b)) = hashes {
+                if !(in_use.contains(hash_a) && in_use.contains(hash_b)) {
+                    remove_old(&path, since, options.dry_run, &mut freed)?;
+                }
+            }
+        }
+    }
+
+    Ok(freed)
+}
+
+/// Remove `path` unless it was written after `since`.
+fn remove_old(path: &Path, since: DateTime<Utc>, dry_run: bool, freed: &mut Freed) -> io::Result<()> {
+    let metadata = fs::metadata(path)?;
+    if DateTime::<Utc>::from(metadata.modified()?) >= since {
+        return Ok(());
+    }
+
+    if dry_run {
+        info!("Would delete {:?}", path);
+    } else {
+        info!("Deleting {:?}", path);
+        fs::remove_file(path)?;
+    }
+    freed.files += 1;
+    freed.bytes += metadata.len();
+    Ok(())
+}
+
+/// `bytes` in the largest unit it is at least one of, e.g. `1.5 GiB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[derive(Debug)]
+pub enum GcError {
+    Database(DatabaseError),
+    Io(io::Error),
+}
+
+impl From<DatabaseError> for GcError {
+    fn from(e: DatabaseError) -> Self {
+        GcError::Database(e)
+    }
+}
+
+impl From<io::Error> for GcError {
+    fn from(e: io::Error) -> Self {
+        GcError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::messages::{BuildRequest, BuildRequestV1, BuildResponseV1, BuildStatusV1, HashesV1};
+
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    fn unreproducible(revision: &str, hash_a: &str, hash_b: &str) -> BuildResponseV1 {
+        let mut hashes = HashesV1::new();
+        hashes.insert("out".to_string(), (hash_a.repeat(64), hash_b.repeat(64)));
+        BuildResponseV1 {
+            request: BuildRequest::V1(BuildRequestV1 {
+                nixpkgs_revision: revision.to_string(),
+                nixpkgs_sha256sum: "bogus".to_string(),
+                result_url: "bogus".to_string(),
+                subsets: Default::default(),
+            }),
+            drv: format!("/nix/store/{}-drv.drv", "0".repeat(32)),
+            status: BuildStatusV1::Unreproducible(hashes),
+        }
+    }
+
+    fn age(path: &Path, days: i64) {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified((Utc::now() - Duration::days(days)).into())
+            .unwrap();
+    }
+
+    /// A stored file of 4 bytes named by `hash`, written `days` ago
+    fn object(cas: &Path, hash: &str, days: i64) -> PathBuf {
+        let id = hash.repeat(64);
+        let dir = cas.join(&id[0..2]).join(&id[2..4]);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(&id);
+        fs::write(&path, "NAR!").unwrap();
+        age(&path, days);
+        path
+    }
+
+    fn diff(report_dir: &Path, hash_a: &str, hash_b: &str, days: i64) -> PathBuf {
+        let dir = report_dir.join("diff");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{}-{}.html", hash_a.repeat(64), hash_b.repeat(64)));
+        fs::write(&path, "diff").unwrap();
+        age(&path, days);
+        path
+    }
+
+    #[test]
+    fn only_what_no_recent_result_refers_to_is_deleted() {
+        let dir = TempDir::new("gc").unwrap();
+        let options = GcOptions {
+            keep_revisions: 1,
+            keep_days: 30,
+            dry_run: true,
+            database: dir.path().join("r13y.sqlite"),
+            legacy_logs: dir.path().join("logs"),
+            cas: dir.path().join("cas"),
+            report_dir: dir.path().join("report"),
+        };
+
+        // Both checked before the window: the latest revision, whose
+        // results refer to c and d, and an older one's to a and b
+        fs::create_dir(&options.legacy_logs).unwrap();
+        let revisions = [("new", "c", "d", 60), ("old", "a", "b", 365)];
+        for (revision, hash_a, hash_b, days) in revisions.iter() {
+            let log = options
+                .legacy_logs
+                .join(format!("reproducibility-log-{}.json", revision));
+            let results = [unreproducible(revision, hash_a, hash_b)];
+            fs::write(&log, serde_json::to_vec(&results).unwrap()).unwrap();
+            age(&log, *days);
+        }
+
+        let kept = [
+            object(&options.cas, "c", 365),
+            // Not referred to, but possibly by a check still running
+            object(&options.cas, "e", 1),
+            object(&options.report_dir.join("cas"), "d", 365),
+            diff(&options.report_dir, "c", "d", 365),
+        ];
+        let deleted = [
+            object(&options.cas, "a", 365),
+            object(&options.cas, "f", 365),
+            object(&options.report_dir.join("cas"), "b", 365),
+            diff(&options.report_dir, "a", "b", 365),
+            diff(&options.report_dir, "c", "f", 365),
+        ];
+
+        let freed = gc(&options).unwrap();
+        assert_eq!(freed.files, 5);
+        assert_eq!(freed.bytes, 3 * 4 + 2 * 4);
+        for path in kept.iter().chain(deleted.iter()) {
+            assert!(path.exists(), "{:?} was deleted by a dry run", path);
+        }
+
+        let again = gc(&GcOptions {
+            dry_run: false,
+            ..options
+        })
+        .unwrap();
+        assert_eq!((again.files, again.bytes), (freed.files, freed.bytes));
+        for path in kept.iter() {
+            assert!(path.exists(), "{:?} was deleted", path);
+        }
+        for path in deleted.iter() {
+            assert!(!path.exists(), "{:?} was kept", path);
+        }
+    }
+
+    #[test]
+    fn sizes_are_formatted_in_their_largest_unit() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
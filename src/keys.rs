@@ -0,0 +1,62 @@
+//! ed25519 keys for signing messages.
+//!
+//! Secret keys are stored on disk as the hex-encoded 32 byte seed,
+//! public keys are passed around as hex strings.
+
+use ed25519_dalek::{SecretKey, SigningKey, VerifyingKey, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
+use rand::RngCore;
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+pub fn generate_signing_key() -> SigningKey {
+    let mut seed: SecretKey = [0; SECRET_KEY_LENGTH];
+    rand::thread_rng().fill_bytes(&mut seed);
+    SigningKey::from_bytes(&seed)
+}
+
+pub fn load_signing_key(path: &Path) -> Result<SigningKey, KeyError> {
+    let contents = fs::read_to_string(path)?;
+    let mut seed: SecretKey = [0; SECRET_KEY_LENGTH];
+    hex::decode_to_slice(contents.trim(), &mut seed).map_err(|_| KeyError::Malformed)?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+pub fn write_signing_key(path: &Path, key: &SigningKey) -> Result<(), KeyError> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", hex::encode(key.to_bytes()))?;
+    Ok(())
+}
+
+pub fn encode_public_key(key: &VerifyingKey) -> String {
+    hex::encode(key.as_bytes())
+}
+
+pub fn parse_public_key(key: &str) -> Result<VerifyingKey, KeyError> {
+    let mut bytes = [0; PUBLIC_KEY_LENGTH];
+    hex::decode_to_slice(key.trim(), &mut bytes).map_err(|_| KeyError::Malformed)?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| KeyError::Malformed)
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    Io(io::Error),
+    Malformed,
+}
+
+impl From<io::Error> for KeyError {
+    fn from(e: io::Error) -> Self {
+        KeyError::Io(e)
+    }
+}
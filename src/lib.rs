@@ -5,10 +5,17 @@ extern crate log;
 
 pub mod cas;
 pub mod check;
+pub mod coordinator;
+pub mod database;
 pub mod derivation;
 pub mod diffoscope;
 pub mod eval;
+pub mod gc;
 pub mod glue;
+pub mod keys;
 pub mod messages;
+pub mod nar;
 pub mod report;
 pub mod store;
+pub mod upload;
+pub mod verify;
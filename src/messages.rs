@@ -10,7 +10,9 @@
 //! A central r13y coordination server has a Signed<BuildRequest>
 //! message at URL:
 //!
-//!     https://compute.r13y.com/latest
+//! ```text
+//! https://compute.r13y.com/latest
+//! ```
 //!
 //! Verifiers will fetch the Signed<BuildRequest> URL for instructions
 //! and:
@@ -30,8 +32,20 @@
 //! The Coordination server will periodically scan for new uploads
 //! and use them to produce a build result diff.
 
-use serde::Serialize;
-use std::{collections::HashMap, path::Path};
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{
+    de::{self, DeserializeOwned},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt,
+    marker::PhantomData,
+    path::Path,
+    str::FromStr,
+};
 
 /// A build request is located at an HTTPS endpoint, the client fetches
 /// the request, instantiates all the derivations, and then operates
@@ -39,6 +53,66 @@ use std::{collections::HashMap, path::Path};
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum BuildRequest {
     V1(BuildRequestV1),
+    V2(BuildRequestV2),
+}
+
+impl BuildRequest {
+    pub fn nixpkgs_revision(&self) -> &str {
+        match self {
+            BuildRequest::V1(req) => &req.nixpkgs_revision,
+            BuildRequest::V2(req) => &req.nixpkgs_revision,
+        }
+    }
+
+    pub fn nixpkgs_sha256sum(&self) -> &str {
+        match self {
+            BuildRequest::V1(req) => &req.nixpkgs_sha256sum,
+            BuildRequest::V2(req) => &req.nixpkgs_sha256sum,
+        }
+    }
+
+    pub fn result_url(&self) -> &str {
+        match self {
+            BuildRequest::V1(req) => &req.result_url,
+            BuildRequest::V2(req) => &req.result_url,
+        }
+    }
+
+    pub fn subsets(&self) -> &HashMap<Subset, Attrs> {
+        match self {
+            BuildRequest::V1(req) => &req.subsets,
+            BuildRequest::V2(req) => &req.subsets,
+        }
+    }
+
+    /// V1 requests don't name a system, leaving it to the verifier.
+    pub fn system(&self) -> Option<&str> {
+        match self {
+            BuildRequest::V1(_) => None,
+            BuildRequest::V2(req) => Some(&req.system),
+        }
+    }
+
+    pub fn deadline(&self) -> Option<DateTime<Utc>> {
+        match self {
+            BuildRequest::V1(_) => None,
+            BuildRequest::V2(req) => req.deadline,
+        }
+    }
+
+    pub fn variations(&self) -> &[Variation] {
+        match self {
+            BuildRequest::V1(_) => &[],
+            BuildRequest::V2(req) => &req.variations,
+        }
+    }
+
+    pub fn overlays(&self) -> &[Overlay] {
+        match self {
+            BuildRequest::V1(_) => &[],
+            BuildRequest::V2(req) => &req.overlays,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -62,21 +136,247 @@ pub struct BuildRequestV1 {
     pub subsets: HashMap<Subset, Attrs>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BuildRequestV2 {
+    /// Identifies the request, so responses can be matched to it
+    pub request_id: String,
+
+    /// Nixpkgs revision to fetch for the build
+    pub nixpkgs_revision: String,
+
+    /// sha256 of Nixpkgs to support pure evaluation mode, and
+    /// a double-check since we're running on people's computers
+    pub nixpkgs_sha256sum: String,
+
+    /// the URL to POST the BuildResponse to
+    pub result_url: String,
+
+    /// A map of files and attributes to build, see
+    /// `BuildRequestV1::subsets`.
+    pub subsets: HashMap<Subset, Attrs>,
+
+    /// The system to evaluate for, e.g. `x86_64-linux`
+    pub system: String,
+
+    /// Results are no longer wanted after this time, so no new
+    /// builds should be started
+    pub deadline: Option<DateTime<Utc>>,
+
+    /// How the second build should differ from the first
+    pub variations: Vec<Variation>,
+
+    /// Overlays applied to Nixpkgs for the `NixpkgsWithOverlays`
+    /// subset, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overlays: Vec<Overlay>,
+}
+
+impl BuildRequestV2 {
+    /// An id which is the same for requests with the same content:
+    /// the revision, and a hash of the rest of the request apart from
+    /// its id.
+    pub fn content_id(&self) -> Result<String, serde_json::Error> {
+        let without_id = BuildRequestV2 {
+            request_id: String::new(),
+            ..self.clone()
+        };
+        let mut digest = Sha256::new();
+        digest.input(canonical_json(&without_id)?.as_bytes());
+        let hash = format!("{:x}", digest.result());
+        Ok(format!("{}-{}", self.nixpkgs_revision, &hash[..16]))
+    }
+}
+
+/// A change to the environment of the second build, to shake out
+/// nondeterminism the first build's environment hides.
+///
+/// Shifting the clock, or varying the time zone, locale, hostname,
+/// user or umask, is left for later. The nix daemon's sandbox pins
+/// them, so they need a build hook or daemon support rather than
+/// options to `nix-store`, and `--variation` refuses them until then.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Variation {
+    /// Shuffle the order of directory entries, using disorderfs
+    Disorderfs,
+    /// Build with this many cores instead
+    Cores { cores: u16 },
+}
+
+/// A group of derivations to check, named by the server and turned
+/// into a file or attribute path by the verifier.
+///
+/// Requests use subsets as map keys, so they are serialized as
+/// strings: `Nixpkgs`, `NixOSReleaseCombined`, `NixpkgsWithOverlays`, or
+/// `flake:<locked flake reference>#<outputs>`.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Subset {
     Nixpkgs,
     NixOSReleaseCombined,
+    /// Nixpkgs with the request's overlays applied
+    NixpkgsWithOverlays,
+    /// Outputs of a flake, fetched at a fixed revision
+    Flake {
+        flake: LockedFlakeRef,
+        outputs: FlakeOutputs,
+    },
+}
+
+impl Subset {
+    /// The file in Nixpkgs this subset is evaluated from
+    pub fn file(&self) -> Option<&'static Path> {
+        match self {
+            Subset::Nixpkgs | Subset::NixpkgsWithOverlays => Some(Path::new("./default.nix")),
+            Subset::NixOSReleaseCombined => Some(Path::new("./nixos/release-combined.nix")),
+            Subset::Flake { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Subset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Subset::Nixpkgs => write!(f, "Nixpkgs"),
+            Subset::NixpkgsWithOverlays => write!(f, "NixpkgsWithOverlays"),
+            Subset::NixOSReleaseCombined => write!(f, "NixOSReleaseCombined"),
+            Subset::Flake { flake, outputs } => write!(f, "flake:{}#{}", flake, outputs),
+        }
+    }
+}
+
+impl FromStr for Subset {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Subset, &'static str> {
+        match s {
+            "Nixpkgs" => Ok(Subset::Nixpkgs),
+            "NixpkgsWithOverlays" => Ok(Subset::NixpkgsWithOverlays),
+            "NixOSReleaseCombined" => Ok(Subset::NixOSReleaseCombined),
+            _ => {
+                let flake = s.strip_prefix("flake:").ok_or("unknown subset")?;
+                let (flake, outputs) = flake.rsplit_once('#').ok_or("flake subset without outputs")?;
+                Ok(Subset::Flake {
+                    flake: flake.parse()?,
+                    outputs: outputs.parse()?,
+                })
+            }
+        }
+    }
+}
+
+impl Serialize for Subset {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
 }
-impl Into<&'static Path> for Subset {
-    fn into(self) -> &'static Path {
-        (&self).into()
+
+impl<'de> Deserialize<'de> for Subset {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Subset, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
     }
 }
-impl<'a> Into<&'static Path> for &'a Subset {
-    fn into(self) -> &'static Path {
+
+/// A tarball whose `default.nix` is a Nixpkgs overlay, pinned by the
+/// sha256 of its unpacked contents like `builtins.fetchTarball`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
+pub struct Overlay {
+    /// An https:// URL
+    pub url: String,
+    pub sha256: String,
+}
+
+/// Which outputs of a flake a subset checks. Attributes are looked up
+/// below `packages.<system>`, or are names of NixOS configurations
+/// whose `config.system.build.toplevel` is checked.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum FlakeOutputs {
+    Packages,
+    NixOSConfigurations,
+}
+
+impl fmt::Display for FlakeOutputs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Subset::Nixpkgs => Path::new("./default.nix"),
-            Subset::NixOSReleaseCombined => Path::new("./nixos/release-combined.nix"),
+            FlakeOutputs::Packages => write!(f, "packages"),
+            FlakeOutputs::NixOSConfigurations => write!(f, "nixosConfigurations"),
+        }
+    }
+}
+
+impl FromStr for FlakeOutputs {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<FlakeOutputs, &'static str> {
+        match s {
+            "packages" => Ok(FlakeOutputs::Packages),
+            "nixosConfigurations" => Ok(FlakeOutputs::NixOSConfigurations),
+            _ => Err("flake outputs must be packages or nixosConfigurations"),
+        }
+    }
+}
+
+/// A reference to a flake in a forge or git repository at a fixed
+/// commit, e.g. `github:owner/repo/<commit>` or
+/// `git+https://example.org/repo?rev=<commit>`. Only remote,
+/// locked references are accepted, so a server can't point a
+/// verifier at its local files or at a moving branch.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct LockedFlakeRef(String);
+
+impl LockedFlakeRef {
+    /// The commit the reference is locked to
+    pub fn revision(&self) -> &str {
+        let (scheme, rest) = self.0.split_once(':').unwrap();
+        let (location, query) = rest.split_once('?').unwrap_or((rest, ""));
+        if scheme == "git+https" {
+            query
+                .split('&')
+                .find_map(|param| param.strip_prefix("rev="))
+                .unwrap()
+        } else {
+            location.rsplit('/').next().unwrap()
+        }
+    }
+}
+
+impl fmt::Display for LockedFlakeRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for LockedFlakeRef {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<LockedFlakeRef, &'static str> {
+        let is_commit = |rev: &str| rev.len() == 40 && rev.bytes().all(|b| b.is_ascii_hexdigit());
+        let (scheme, rest) = s.split_once(':').ok_or("flake reference without a type")?;
+        let (location, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let locked = match scheme {
+            "github" | "gitlab" | "sourcehut" => {
+                let parts: Vec<&str> = location.split('/').collect();
+                parts.len() == 3
+                    && parts.iter().all(|part| !part.is_empty() && *part != "..")
+                    && is_commit(parts[2])
+            }
+            "git+https" => {
+                // `revision` takes the first rev, so that is the one
+                // which must be a commit
+                location
+                    .strip_prefix("//")
+                    .is_some_and(|url| !url.is_empty() && !url.starts_with('/'))
+                    && query
+                        .split('&')
+                        .find_map(|param| param.strip_prefix("rev="))
+                        .is_some_and(is_commit)
+            }
+            _ => return Err("flake references must be github:, gitlab:, sourcehut: or git+https:"),
+        };
+        if locked && !s.contains('#') {
+            Ok(LockedFlakeRef(s.to_string()))
+        } else {
+            Err("flake references must be locked to a commit")
         }
     }
 }
@@ -93,9 +393,33 @@ pub type Attr = Vec<String>;
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum BuildResponse {
     V1(BuildResponseV1),
+    EvaluationFailureV1(EvaluationFailureV1),
+    V2(BuildResponseV2),
+}
+
+impl BuildResponse {
+    pub fn request(&self) -> &BuildRequest {
+        match self {
+            BuildResponse::V1(response) => &response.request,
+            BuildResponse::EvaluationFailureV1(failure) => &failure.request,
+            BuildResponse::V2(response) => &response.request,
+        }
+    }
+
+    /// The result of checking a derivation, as a V2 whichever version
+    /// it was sent as
+    pub fn result(&self) -> Option<BuildResponseV2> {
+        match self {
+            BuildResponse::V1(response) => Some(response.clone().into()),
+            BuildResponse::EvaluationFailureV1(_) => None,
+            BuildResponse::V2(response) => Some(response.clone()),
+        }
+    }
 }
 
-/// !!! does not account for evaluation failure !!!
+/// The result of checking one derivation, as it was sent before
+/// BuildResponseV2. Verifiers still send it in reply to a
+/// BuildRequest::V1, as coordinators which send those can't read V2.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BuildResponseV1 {
     /// Original, inciting request
@@ -105,10 +429,10 @@ pub struct BuildResponseV1 {
     pub drv: String,
 
     /// Result of the build
-    pub status: BuildStatus,
+    pub status: BuildStatusV1,
 }
 
-/// Build results are from the following table:
+/// V1 build results are from the following table:
 ///
 /// |                | nix-build | nix-build --check -K | has .check dir? |
 /// |----------------|-----------|----------------------|-----------------|
@@ -117,15 +441,162 @@ pub struct BuildResponseV1 {
 /// | unreproducible | success   | failed               | yes             |
 /// | reproducible   | success   | success              | n/a             |
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum BuildStatusV1 {
+    FirstFailed,
+    SecondFailed,
+    Unreproducible(HashesV1),
+    Reproducible,
+}
+
+/// The sha256sums of each output's NAR, from the first and the second
+/// build
+pub type HashesV1 = HashMap<String, (Sha256Sum, Sha256Sum)>;
+
+/// The result of checking one derivation. Evaluation failures are
+/// reported separately, as an EvaluationFailureV1.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BuildResponseV2 {
+    /// Original, inciting request
+    pub request: BuildRequest,
+
+    /// Derivation name, ie: `/nix/store/hash-name.drv`
+    pub drv: String,
+
+    /// Result of the build
+    pub status: BuildStatus,
+
+    /// Variations the --check builds were done with
+    #[serde(default)]
+    pub variations: Vec<Variation>,
+}
+
+impl BuildResponseV2 {
+    /// The message to send this as: a V1 in reply to a V1 request, or
+    /// None if V1 can't express the result.
+    pub fn into_message(self) -> Option<BuildResponse> {
+        if let BuildRequest::V1(_) = self.request {
+            self.into_v1().map(BuildResponse::V1)
+        } else {
+            Some(BuildResponse::V2(self))
+        }
+    }
+
+    fn into_v1(self) -> Option<BuildResponseV1> {
+        let status = match self.status {
+            BuildStatus::FirstFailed => BuildStatusV1::FirstFailed,
+            BuildStatus::SecondFailed => BuildStatusV1::SecondFailed,
+            BuildStatus::Reproducible => BuildStatusV1::Reproducible,
+            // V1 only lists the outputs which differed, each with the
+            // two hashes it was built with. An output built the same
+            // both times is left out rather than listed with one hash
+            // twice, and more than two hashes can't be told.
+            BuildStatus::Unreproducible(hashes) => {
+                let mut differing = HashesV1::new();
+                for (output, hashes) in hashes.into_iter() {
+                    match hashes.as_slice() {
+                        [_] => {}
+                        [first, second] => {
+                            differing.insert(output, (first.clone(), second.clone()));
+                        }
+                        _ => return None,
+                    }
+                }
+                if differing.is_empty() {
+                    return None;
+                }
+                BuildStatusV1::Unreproducible(differing)
+            }
+            BuildStatus::TimedOut | BuildStatus::Flaky { .. } => return None,
+        };
+        if !self.variations.is_empty() {
+            return None;
+        }
+
+        Some(BuildResponseV1 {
+            request: self.request,
+            drv: self.drv,
+            status,
+        })
+    }
+}
+
+impl From<BuildResponseV1> for BuildResponseV2 {
+    fn from(response: BuildResponseV1) -> BuildResponseV2 {
+        let status = match response.status {
+            BuildStatusV1::FirstFailed => BuildStatus::FirstFailed,
+            BuildStatusV1::SecondFailed => BuildStatus::SecondFailed,
+            BuildStatusV1::Reproducible => BuildStatus::Reproducible,
+            BuildStatusV1::Unreproducible(hashes) => BuildStatus::Unreproducible(
+                hashes
+                    .into_iter()
+                    .map(|(output, (first, second))| (output, vec![first, second]))
+                    .collect(),
+            ),
+        };
+        BuildResponseV2 {
+            request: response.request,
+            drv: response.drv,
+            status,
+            variations: vec![],
+        }
+    }
+}
+
+/// Instantiating one of the requested subsets failed, so none of
+/// its derivations were checked.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EvaluationFailureV1 {
+    /// Original, inciting request
+    pub request: BuildRequest,
+
+    /// The subset which failed to evaluate
+    pub subset: Subset,
+
+    /// The attributes of the subset which were being evaluated
+    pub attrs: Attrs,
+
+    /// What nix-instantiate printed on stderr
+    pub stderr: String,
+}
+
+/// Build results are from the following table:
+///
+/// |                | nix-build | nix-build --check -K   | has .check dir? |
+/// |----------------|-----------|------------------------|-----------------|
+/// | first-failed   | failed    | n/a                    | n/a             |
+/// | second-failed  | success   | failed                 | no              |
+/// | unreproducible | success   | failed, every round    | yes             |
+/// | flaky          | success   | failed, in some rounds | yes             |
+/// | reproducible   | success   | success, every round   | n/a             |
+/// | timed-out      | success   | timed out, twice       | n/a             |
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum BuildStatus {
     FirstFailed,
     SecondFailed,
     Unreproducible(Hashes),
     Reproducible,
+    TimedOut,
+    Flaky {
+        /// How many --check builds were done
+        rounds: u32,
+        /// How many of them differed from the first build
+        disagreed: u32,
+        hashes: Hashes,
+    },
 }
 
-/// A list of sha256sums of build products
-pub type Hashes = HashMap<String, (Sha256Sum, Sha256Sum)>;
+impl BuildStatus {
+    pub fn hashes(&self) -> Option<&Hashes> {
+        match self {
+            BuildStatus::Unreproducible(hashes) | BuildStatus::Flaky { hashes, .. } => Some(hashes),
+            _ => None,
+        }
+    }
+}
+
+/// Every distinct sha256sum of each output's NAR, the first build's
+/// first. Those converted from a BuildResponseV1 have exactly two.
+pub type Hashes = HashMap<String, Vec<Sha256Sum>>;
 pub type Sha256Sum = String;
 pub type UploadURL = String;
 
@@ -145,12 +616,357 @@ pub enum BuildUploadTokens {
 ///    an upload token will not be provided.
 pub type BuildUploadTokensV1 = HashMap<Sha256Sum, UploadURL>;
 
+/// A type of message which is sent signed. Each has its own domain,
+/// so a signature over one type of message is never valid for
+/// another, even where their JSON happens to look the same.
+pub trait SignedMessage: Serialize + DeserializeOwned {
+    const DOMAIN: &'static str;
+}
+
+impl SignedMessage for BuildRequest {
+    const DOMAIN: &'static str = "BuildRequest";
+}
+
+impl SignedMessage for BuildResponse {
+    const DOMAIN: &'static str = "BuildResponse";
+}
+
+impl SignedMessage for BuildUploadTokens {
+    const DOMAIN: &'static str = "BuildUploadTokens";
+}
+
+/// A message signed by the party which produced it.
+///
+/// The signature covers the message's domain, see `SignedMessage`,
+/// followed by the canonical serialization of the value: JSON with
+/// every object's keys sorted and no insignificant whitespace. The
+/// canonical bytes are carried verbatim, so the receiver verifies
+/// exactly what was signed and only then decodes it.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Signed<T>
+pub struct Signed<T> {
+    /// Hex-encoded ed25519 public key of the signer
+    public_key: String,
+
+    /// Hex-encoded ed25519 signature of `bytes`
+    signature: String,
+
+    /// Canonical serialization of the signed value
+    bytes: String,
+
+    #[serde(skip)]
+    whatever: PhantomData<T>,
+}
+
+impl<T> Signed<T>
 where
-    T: Serialize,
+    T: SignedMessage,
 {
-    public_key: String,
-    bytes: Vec<u8>,
-    whatever: T,
+    pub fn sign(value: &T, keypair: &SigningKey) -> Result<Signed<T>, SignatureError> {
+        let bytes = canonical_json(value)?;
+        let signature = keypair.sign(&Self::signed_bytes(&bytes));
+
+        Ok(Signed {
+            public_key: hex::encode(keypair.verifying_key().as_bytes()),
+            signature: hex::encode(signature.to_bytes()),
+            bytes,
+            whatever: PhantomData,
+        })
+    }
+
+    /// Check the message was signed by one of `trusted_keys`, and
+    /// return the value it carries.
+    pub fn verify(&self, trusted_keys: &[VerifyingKey]) -> Result<T, SignatureError> {
+        let public_key = crate::keys::parse_public_key(&self.public_key)
+            .map_err(|_| SignatureError::MalformedKey)?;
+        if !trusted_keys.contains(&public_key) {
+            return Err(SignatureError::UntrustedKey(self.public_key.clone()));
+        }
+
+        let mut signature = [0; Signature::BYTE_SIZE];
+        hex::decode_to_slice(&self.signature, &mut signature)
+            .map_err(|_| SignatureError::MalformedSignature)?;
+        public_key
+            .verify_strict(&Self::signed_bytes(&self.bytes), &Signature::from_bytes(&signature))
+            .map_err(|_| SignatureError::BadSignature)?;
+
+        Ok(serde_json::from_str(&self.bytes)?)
+    }
+
+    /// Hex-encoded public key the message claims to be signed by.
+    /// Not trustworthy until `verify` succeeds.
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    /// What the signature is over: `r13y/<domain>`, a NUL byte, and
+    /// the canonical JSON, which can't contain a NUL byte itself
+    fn signed_bytes(bytes: &str) -> Vec<u8> {
+        let mut signed = format!("r13y/{}\0", T::DOMAIN).into_bytes();
+        signed.extend_from_slice(bytes.as_bytes());
+        signed
+    }
+}
+
+/// Serialize through serde_json::Value, whose maps are ordered, so
+/// HashMap iteration order can't change the signed bytes.
+fn canonical_json<T: Serialize>(value: &T) -> Result<String, serde_json::Error> {
+    serde_json::to_string(&serde_json::to_value(value)?)
+}
+
+#[derive(Debug)]
+pub enum SignatureError {
+    Json(serde_json::Error),
+    MalformedKey,
+    MalformedSignature,
+    UntrustedKey(String),
+    BadSignature,
+}
+
+impl From<serde_json::Error> for SignatureError {
+    fn from(e: serde_json::Error) -> Self {
+        SignatureError::Json(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::generate_signing_key;
+
+    fn request() -> BuildRequest {
+        BuildRequest::V1(BuildRequestV1 {
+            nixpkgs_revision: "70503758fb4b37107953dfb03ad7c0cf36ad0435".to_string(),
+            nixpkgs_sha256sum: "15g8xckhzpp84p6gv526hb6c1r286qvn8i14w8msw6172jy3kj3c".to_string(),
+            result_url: "https://compute.r13y.com/result".to_string(),
+            subsets: vec![(Subset::Nixpkgs, Some(vec![vec!["hello".to_string()]]))]
+                .into_iter()
+                .collect(),
+        })
+    }
+
+    #[test]
+    fn signed_messages_verify() {
+        let key = generate_signing_key();
+        let signed = Signed::sign(&request(), &key).unwrap();
+
+        // Through JSON, as it is sent
+        let signed: Signed<BuildRequest> =
+            serde_json::from_str(&serde_json::to_string(&signed).unwrap()).unwrap();
+        let verified = signed.verify(&[key.verifying_key()]).unwrap();
+        assert_eq!(verified.nixpkgs_revision(), request().nixpkgs_revision());
+        assert_eq!(signed.public_key(), crate::keys::encode_public_key(&key.verifying_key()));
+    }
+
+    #[test]
+    fn untrusted_keys_are_refused() {
+        let signed = Signed::sign(&request(), &generate_signing_key()).unwrap();
+        match signed.verify(&[generate_signing_key().verifying_key()]) {
+            Err(SignatureError::UntrustedKey(_)) => {}
+            other => panic!("expected UntrustedKey, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tampered_messages_are_refused() {
+        let key = generate_signing_key();
+        let signed = Signed::sign(&request(), &key).unwrap();
+        let mut json = serde_json::to_value(&signed).unwrap();
+        let bytes = json["bytes"].as_str().unwrap().replace("hello", "hellp");
+        json["bytes"] = bytes.into();
+
+        let tampered: Signed<BuildRequest> = serde_json::from_value(json).unwrap();
+        match tampered.verify(&[key.verifying_key()]) {
+            Err(SignatureError::BadSignature) => {}
+            other => panic!("expected BadSignature, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn signatures_are_bound_to_the_message_type() {
+        let key = generate_signing_key();
+        let tokens: BuildUploadTokensV1 = vec![("a".repeat(64), "https://example.org".to_string())]
+            .into_iter()
+            .collect();
+        let signed = Signed::sign(&BuildUploadTokens::V1(tokens), &key).unwrap();
+
+        // The same bytes and signature, passed off as another type
+        let json = serde_json::to_string(&signed).unwrap();
+        let replayed: Signed<BuildRequest> = serde_json::from_str(&json).unwrap();
+        match replayed.verify(&[key.verifying_key()]) {
+            Err(SignatureError::BadSignature) => {}
+            other => panic!("expected BadSignature, got {:?}", other),
+        }
+    }
+
+    fn request_v2() -> BuildRequestV2 {
+        BuildRequestV2 {
+            request_id: String::new(),
+            nixpkgs_revision: "70503758fb4b37107953dfb03ad7c0cf36ad0435".to_string(),
+            nixpkgs_sha256sum: "15g8xckhzpp84p6gv526hb6c1r286qvn8i14w8msw6172jy3kj3c".to_string(),
+            result_url: "bogus".to_string(),
+            subsets: vec![
+                (Subset::Nixpkgs, Some(vec![vec!["hello".to_string()]])),
+                (Subset::NixOSReleaseCombined, None),
+            ]
+            .into_iter()
+            .collect(),
+            system: "x86_64-linux".to_string(),
+            deadline: None,
+            variations: vec![Variation::Disorderfs],
+            overlays: vec![],
+        }
+    }
+
+    #[test]
+    fn content_ids_only_depend_on_the_content() {
+        let request = request_v2();
+        let id = request.content_id().unwrap();
+        assert!(id.starts_with("70503758fb4b37107953dfb03ad7c0cf36ad0435-"));
+
+        // Built again, with the subsets inserted the other way round
+        let mut subsets: Vec<_> = request.subsets.clone().into_iter().collect();
+        subsets.reverse();
+        let mut again = request_v2();
+        again.subsets = subsets.into_iter().collect();
+        again.request_id = "ignored".to_string();
+        assert_eq!(again.content_id().unwrap(), id);
+
+        let mut other = request_v2();
+        other.variations = vec![];
+        assert_ne!(other.content_id().unwrap(), id);
+
+        let mut patched = request_v2();
+        patched.nixpkgs_revision.push_str("-dirty-0123456789ab");
+        assert_ne!(patched.content_id().unwrap(), id);
+    }
+
+    fn response_v2(request: BuildRequest, status: BuildStatus) -> BuildResponseV2 {
+        BuildResponseV2 {
+            request,
+            drv: "/nix/store/00000000000000000000000000000000-hello.drv".to_string(),
+            status,
+            variations: vec![],
+        }
+    }
+
+    #[test]
+    fn responses_to_v1_requests_are_sent_as_v1() {
+        let mut hashes = Hashes::new();
+        hashes.insert("out".to_string(), vec!["a".to_string(), "b".to_string()]);
+        hashes.insert("dev".to_string(), vec!["c".to_string()]);
+        let response = response_v2(request(), BuildStatus::Unreproducible(hashes.clone()));
+
+        let sent = serde_json::to_value(response.into_message().unwrap()).unwrap();
+        let v1 = &sent["V1"];
+        assert_eq!(v1["status"]["Unreproducible"]["out"], serde_json::json!(["a", "b"]));
+        // Only differing outputs are listed in V1
+        assert!(v1["status"]["Unreproducible"].get("dev").is_none());
+        assert!(v1.get("variations").is_none());
+
+        // What a V1 request's sender can't read isn't sent at all
+        let timed_out = response_v2(request(), BuildStatus::TimedOut);
+        assert!(timed_out.into_message().is_none());
+        hashes.insert("out".to_string(), vec!["a".to_string(), "b".to_string(), "d".to_string()]);
+        let three_hashes = response_v2(request(), BuildStatus::Unreproducible(hashes.clone()));
+        assert!(three_hashes.into_message().is_none());
+        let flaky = response_v2(
+            request(),
+            BuildStatus::Flaky {
+                rounds: 3,
+                disagreed: 1,
+                hashes,
+            },
+        );
+        assert!(flaky.into_message().is_none());
+        let mut same = Hashes::new();
+        same.insert("out".to_string(), vec!["a".to_string()]);
+        let nothing_differed = response_v2(request(), BuildStatus::Unreproducible(same));
+        assert!(nothing_differed.into_message().is_none());
+    }
+
+    #[test]
+    fn responses_to_v2_requests_are_sent_as_v2() {
+        let request = BuildRequest::V2(request_v2());
+        let response = response_v2(request, BuildStatus::TimedOut);
+        match response.into_message() {
+            Some(BuildResponse::V2(response)) => assert_eq!(response.status, BuildStatus::TimedOut),
+            other => panic!("expected a V2 response, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn v1_responses_are_still_read() {
+        let sent = serde_json::json!({
+            "V1": {
+                "request": request(),
+                "drv": "/nix/store/00000000000000000000000000000000-hello.drv",
+                "status": {"Unreproducible": {"out": ["a", "b"]}}
+            }
+        });
+        let response: BuildResponse = serde_json::from_value(sent).unwrap();
+        let result = response.result().unwrap();
+        assert_eq!(result.status.hashes().unwrap()["out"], vec!["a", "b"]);
+        assert!(result.variations.is_empty());
+    }
+
+    const COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn flake_references_locked_to_a_commit_are_accepted() {
+        for reference in [
+            format!("github:owner/repo/{}", COMMIT),
+            format!("gitlab:group/project/{}", COMMIT),
+            format!("sourcehut:~user/repo/{}", COMMIT),
+            format!("github:owner/repo/{}?dir=sub", COMMIT),
+            format!("git+https://example.org/repo?rev={}", COMMIT),
+            format!("git+https://example.org/repo.git?ref=main&rev={}", COMMIT),
+        ]
+        .iter()
+        {
+            let flake: LockedFlakeRef = reference.parse().unwrap();
+            assert_eq!(flake.revision(), COMMIT);
+            assert_eq!(flake.to_string(), *reference);
+        }
+    }
+
+    #[test]
+    fn flake_references_which_arent_remote_and_locked_are_refused() {
+        let short = &COMMIT[..39];
+        let not_hex = format!("{}g", &COMMIT[..39]);
+        let untyped = "flake reference without a type";
+        let unsupported = "flake references must be github:, gitlab:, sourcehut: or git+https:";
+        let unlocked = "flake references must be locked to a commit";
+        for (reference, error) in [
+            ("nixpkgs".to_string(), untyped),
+            (format!("path:/etc/{}", COMMIT), unsupported),
+            (format!("git+file:///repo?rev={}", COMMIT), unsupported),
+            (format!("git+http://example.org/repo?rev={}", COMMIT), unsupported),
+            (format!("tarball+https://example.org/{}.tar.gz", COMMIT), unsupported),
+            // Branches and tags move
+            ("github:owner/repo".to_string(), unlocked),
+            ("github:owner/repo/main".to_string(), unlocked),
+            (format!("github:owner/repo/{}", short), unlocked),
+            (format!("github:owner/repo/{}", not_hex), unlocked),
+            (format!("github:owner/{}", COMMIT), unlocked),
+            (format!("github:owner/repo/extra/{}", COMMIT), unlocked),
+            (format!("github:owner//{}", COMMIT), unlocked),
+            (format!("github:../repo/{}", COMMIT), unlocked),
+            ("git+https://example.org/repo".to_string(), unlocked),
+            ("git+https://example.org/repo?ref=main".to_string(), unlocked),
+            (format!("git+https://example.org/repo?rev=main&rev={}", COMMIT), unlocked),
+            (format!("git+https:example.org/repo?rev={}", COMMIT), unlocked),
+            (format!("git+https:///repo?rev={}", COMMIT), unlocked),
+            // The attribute is given separately
+            (format!("github:owner/repo/{}#hello", COMMIT), unlocked),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                reference.parse::<LockedFlakeRef>(),
+                Err(*error),
+                "{} should be refused",
+                reference
+            );
+        }
+    }
 }
@@ -0,0 +1,446 @@
+//! Nix ARchive serialization, the format `nix dump-path` writes and
+//! `nix-store --restore` reads.
+//!
+//! Every token is a string: a little-endian u64 length, the bytes,
+//! and zero padding up to a multiple of 8. A file system object is
+//!
+//! ```text
+//! "(" "type" "regular" ["executable" ""] "contents" <contents> ")"
+//! "(" "type" "symlink" "target" <target> ")"
+//! "(" "type" "directory" ("entry" "(" "name" <name> "node" <object> ")")* ")"
+//! ```
+//!
+//! with directory entries sorted by name, and the whole archive
+//! prefixed by "nix-archive-1".
+
+use std::{
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{symlink, OpenOptionsExt, PermissionsExt},
+    },
+    path::Path,
+};
+
+const MAGIC: &str = "nix-archive-1";
+
+/// Don't allocate more than this for a token which isn't file
+/// contents, so a corrupt length can't exhaust memory.
+const MAX_TOKEN_LENGTH: u64 = 4096;
+
+/// Serialize `path` as a NAR into `out`.
+pub fn dump<W: Write>(path: &Path, out: W) -> io::Result<()> {
+    let mut out = BufWriter::new(out);
+    write_str(&mut out, MAGIC)?;
+    dump_node(path, &mut out)?;
+    out.flush()
+}
+
+fn dump_node<W: Write>(path: &Path, out: &mut W) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+
+    write_str(out, "(")?;
+    write_str(out, "type")?;
+    if file_type.is_symlink() {
+        write_str(out, "symlink")?;
+        write_str(out, "target")?;
+        write_bytes(out, fs::read_link(path)?.as_os_str().as_bytes())?;
+    } else if file_type.is_file() {
+        write_str(out, "regular")?;
+        if metadata.permissions().mode() & 0o100 != 0 {
+            write_str(out, "executable")?;
+            write_str(out, "")?;
+        }
+        write_str(out, "contents")?;
+
+        let length = metadata.len();
+        write_u64(out, length)?;
+        let copied = io::copy(&mut File::open(path)?.take(length), out)?;
+        if copied != length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{:?} shrank while it was being read", path),
+            ));
+        }
+        write_padding(out, length)?;
+    } else if file_type.is_dir() {
+        write_str(out, "directory")?;
+
+        let mut entries = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+
+        for name in entries {
+            write_str(out, "entry")?;
+            write_str(out, "(")?;
+            write_str(out, "name")?;
+            write_bytes(out, name.as_bytes())?;
+            write_str(out, "node")?;
+            dump_node(&path.join(&name), out)?;
+            write_str(out, ")")?;
+        }
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is not a regular file, symlink or directory", path),
+        ));
+    }
+    write_str(out, ")")
+}
+
+fn write_u64<W: Write>(out: &mut W, n: u64) -> io::Result<()> {
+    out.write_all(&n.to_le_bytes())
+}
+
+fn write_padding<W: Write>(out: &mut W, length: u64) -> io::Result<()> {
+    let padding = (8 - length % 8) % 8;
+    out.write_all(&[0; 8][..padding as usize])
+}
+
+fn write_bytes<W: Write>(out: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_u64(out, bytes.len() as u64)?;
+    out.write_all(bytes)?;
+    write_padding(out, bytes.len() as u64)
+}
+
+fn write_str<W: Write>(out: &mut W, s: &str) -> io::Result<()> {
+    write_bytes(out, s.as_bytes())
+}
+
+/// Unpack the NAR read from `input` to `dest`, which must not exist
+/// yet.
+pub fn restore<R: Read>(input: R, dest: &Path) -> io::Result<()> {
+    let mut input = BufReader::new(input);
+    expect(&mut input, MAGIC)?;
+    restore_node(&mut input, dest)
+}
+
+fn restore_node<R: Read>(input: &mut R, dest: &Path) -> io::Result<()> {
+    expect(input, "(")?;
+    expect(input, "type")?;
+
+    match read_token(input)?.as_slice() {
+        b"regular" => {
+            let mut token = read_token(input)?;
+            let executable = token == b"executable";
+            if executable {
+                expect(input, "")?;
+                token = read_token(input)?;
+            }
+            if token != b"contents" {
+                return Err(invalid("contents", &token));
+            }
+
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(if executable { 0o777 } else { 0o666 })
+                .open(dest)?;
+            let length = read_u64(input)?;
+            let copied = io::copy(&mut input.take(length), &mut file)?;
+            if copied != length {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            read_padding(input, length)?;
+            expect(input, ")")
+        }
+        b"symlink" => {
+            expect(input, "target")?;
+            let target = read_token(input)?;
+            symlink(OsStr::from_bytes(&target), dest)?;
+            expect(input, ")")
+        }
+        b"directory" => {
+            fs::create_dir(dest)?;
+            let mut previous: Option<Vec<u8>> = None;
+            loop {
+                match read_token(input)?.as_slice() {
+                    b")" => return Ok(()),
+                    b"entry" => {}
+                    other => return Err(invalid("entry", other)),
+                }
+                expect(input, "(")?;
+                expect(input, "name")?;
+                let name = read_token(input)?;
+                if name.is_empty()
+                    || name == b"."
+                    || name == b".."
+                    || name.contains(&b'/')
+                    || name.contains(&0)
+                {
+                    return Err(invalid("a file name", &name));
+                }
+                // Sorted and unique, as written by `dump`
+                if previous.as_ref().is_some_and(|previous| *previous >= name) {
+                    return Err(invalid("entries in order", &name));
+                }
+                expect(input, "node")?;
+                restore_node(input, &dest.join(OsStr::from_bytes(&name)))?;
+                expect(input, ")")?;
+                previous = Some(name);
+            }
+        }
+        other => Err(invalid("a file type", other)),
+    }
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_padding<R: Read>(input: &mut R, length: u64) -> io::Result<()> {
+    let mut padding = [0; 8];
+    let padding = &mut padding[..((8 - length % 8) % 8) as usize];
+    input.read_exact(padding)?;
+    if padding.iter().any(|b| *b != 0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "non-zero padding",
+        ));
+    }
+    Ok(())
+}
+
+fn read_token<R: Read>(input: &mut R) -> io::Result<Vec<u8>> {
+    let length = read_u64(input)?;
+    if length > MAX_TOKEN_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("token of {} bytes is too long", length),
+        ));
+    }
+    let mut token = vec![0; length as usize];
+    input.read_exact(&mut token)?;
+    read_padding(input, length)?;
+    Ok(token)
+}
+
+fn expect<R: Read>(input: &mut R, expected: &str) -> io::Result<()> {
+    let token = read_token(input)?;
+    if token == expected.as_bytes() {
+        Ok(())
+    } else {
+        Err(invalid(expected, &token))
+    }
+}
+
+fn invalid(expected: &str, found: &[u8]) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "expected {:?} in NAR, found {:?}",
+            expected,
+            String::from_utf8_lossy(found)
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    /// A NAR written token by token
+    fn nar(tokens: &[&[u8]]) -> Vec<u8> {
+        let mut out = vec![];
+        for token in tokens {
+            write_bytes(&mut out, token).unwrap();
+        }
+        out
+    }
+
+    fn dumped(path: &Path) -> Vec<u8> {
+        let mut out = vec![];
+        dump(path, &mut out).unwrap();
+        out
+    }
+
+    /// A tree with every kind of file system object
+    fn tree(root: &Path) {
+        fs::create_dir(root).unwrap();
+        fs::write(root.join("empty"), b"").unwrap();
+        fs::write(root.join("eight bytes"), b"12345678").unwrap();
+        fs::write(root.join("script"), b"#!/bin/sh\necho hi\n").unwrap();
+        fs::set_permissions(root.join("script"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::create_dir_all(root.join("share/doc/empty dir")).unwrap();
+        fs::write(root.join("share/doc/README"), b"nested\n").unwrap();
+        symlink("../script", root.join("share/link")).unwrap();
+        symlink("/nix/store/does-not-exist", root.join("dangling")).unwrap();
+        fs::write(root.join(OsStr::from_bytes(b"caf\xe9")), b"not UTF-8").unwrap();
+    }
+
+    #[test]
+    fn regular_files_are_dumped_as_nix_does() {
+        let dir = TempDir::new("nar").unwrap();
+        let file = dir.path().join("hello");
+        fs::write(&file, b"hello\n").unwrap();
+
+        assert_eq!(
+            dumped(&file),
+            nar(&[b"nix-archive-1", b"(", b"type", b"regular", b"contents", b"hello\n", b")"])
+        );
+        // Lengths are little-endian and everything is padded to 8 bytes
+        assert_eq!(
+            &dumped(&file)[..24],
+            b"\x0d\0\0\0\0\0\0\0nix-archive-1\0\0\0"
+        );
+
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o500)).unwrap();
+        assert_eq!(
+            dumped(&file),
+            nar(&[
+                b"nix-archive-1",
+                b"(",
+                b"type",
+                b"regular",
+                b"executable",
+                b"",
+                b"contents",
+                b"hello\n",
+                b")"
+            ])
+        );
+    }
+
+    #[test]
+    fn symlinks_are_dumped_as_their_target() {
+        let dir = TempDir::new("nar").unwrap();
+        let link = dir.path().join("link");
+        symlink("some/where", &link).unwrap();
+
+        assert_eq!(
+            dumped(&link),
+            nar(&[b"nix-archive-1", b"(", b"type", b"symlink", b"target", b"some/where", b")"])
+        );
+    }
+
+    #[test]
+    fn directory_entries_are_sorted_bytewise() {
+        let dir = TempDir::new("nar").unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir(&root).unwrap();
+        for name in ["b", "a.b", "B", "a"].iter() {
+            fs::write(root.join(name), b"").unwrap();
+        }
+
+        let mut expected: Vec<&[u8]> = vec![b"nix-archive-1", b"(", b"type", b"directory"];
+        for name in [&b"B"[..], b"a", b"a.b", b"b"].iter() {
+            expected.extend_from_slice(&[
+                b"entry", b"(", b"name", name, b"node", b"(", b"type", b"regular", b"contents",
+                b"", b")", b")",
+            ]);
+        }
+        expected.push(b")");
+        assert_eq!(dumped(&root), nar(&expected));
+    }
+
+    #[test]
+    fn trees_survive_a_round_trip() {
+        let dir = TempDir::new("nar").unwrap();
+        let original = dir.path().join("original");
+        tree(&original);
+        let archive = dumped(&original);
+
+        let restored = dir.path().join("restored");
+        restore(&archive[..], &restored).unwrap();
+        assert_eq!(dumped(&restored), archive);
+
+        assert_eq!(fs::read(restored.join("share/doc/README")).unwrap(), b"nested\n");
+        assert!(restored.join("share/doc/empty dir").is_dir());
+        assert_eq!(
+            fs::read_link(restored.join("share/link")).unwrap(),
+            Path::new("../script")
+        );
+        assert_eq!(
+            fs::read_link(restored.join("dangling")).unwrap(),
+            Path::new("/nix/store/does-not-exist")
+        );
+        let mode = |name: &str| fs::metadata(restored.join(name)).unwrap().permissions().mode();
+        assert_ne!(mode("script") & 0o100, 0);
+        assert_eq!(mode("empty") & 0o111, 0);
+        assert_eq!(
+            fs::read(restored.join(OsStr::from_bytes(b"caf\xe9"))).unwrap(),
+            b"not UTF-8"
+        );
+    }
+
+    #[test]
+    fn restoring_doesnt_overwrite() {
+        let dir = TempDir::new("nar").unwrap();
+        let original = dir.path().join("original");
+        tree(&original);
+
+        assert!(restore(&dumped(&original)[..], &original).is_err());
+    }
+
+    #[test]
+    fn truncated_archives_are_rejected() {
+        let dir = TempDir::new("nar").unwrap();
+        let original = dir.path().join("original");
+        tree(&original);
+        let archive = dumped(&original);
+
+        for length in 0..archive.len() {
+            let restored = dir.path().join(format!("restored-{}", length));
+            assert!(
+                restore(&archive[..length], &restored).is_err(),
+                "accepted the first {} of {} bytes",
+                length,
+                archive.len()
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_archives_are_rejected() {
+        let file = |name: &'static [u8]| -> Vec<&'static [u8]> {
+            vec![
+                b"entry", b"(", b"name", name, b"node", b"(", b"type", b"regular", b"contents",
+                b"", b")", b")",
+            ]
+        };
+        let directory = |entries: Vec<Vec<&'static [u8]>>| -> Vec<u8> {
+            let mut tokens: Vec<&[u8]> = vec![b"nix-archive-1", b"(", b"type", b"directory"];
+            tokens.extend(entries.into_iter().flatten());
+            tokens.push(b")");
+            nar(&tokens)
+        };
+
+        let mut bad_padding = nar(&[b"nix-archive-1", b"(", b"type", b"symlink", b"target", b"x"]);
+        let last = bad_padding.len() - 1;
+        bad_padding[last] = 1;
+
+        let mut too_long = nar(&[b"nix-archive-1", b"(", b"type", b"symlink", b"target"]);
+        too_long.extend_from_slice(&u64::MAX.to_le_bytes());
+
+        let malformed = vec![
+            ("the wrong magic", nar(&[b"nix-archive-2", b"(", b"type", b"symlink", b"target", b"x", b")"])),
+            ("an unknown type", nar(&[b"nix-archive-1", b"(", b"type", b"fifo", b")"])),
+            ("no contents", nar(&[b"nix-archive-1", b"(", b"type", b"regular", b")"])),
+            ("non-zero padding", bad_padding),
+            ("a huge token", too_long),
+            ("an unclosed node", nar(&[b"nix-archive-1", b"(", b"type", b"symlink", b"target", b"x", b"x"])),
+            ("unsorted entries", directory(vec![file(b"b"), file(b"a")])),
+            ("duplicate entries", directory(vec![file(b"a"), file(b"a")])),
+            ("an entry named ..", directory(vec![file(b"..")])),
+            ("an entry with a slash", directory(vec![file(b"a/b")])),
+            ("an empty entry name", directory(vec![file(b"")])),
+        ];
+
+        let dir = TempDir::new("nar").unwrap();
+        for (i, (what, archive)) in malformed.iter().enumerate() {
+            let restored = dir.path().join(format!("restored-{}", i));
+            assert!(restore(&archive[..], &restored).is_err(), "accepted {}", what);
+        }
+        // Nothing escaped the destination
+        assert!(!dir.path().join("a").exists());
+        assert!(!dir.path().join("b").exists());
+    }
+}
@@ -1,11 +1,12 @@
 use chrono::Utc;
 
 use crate::{
-    cas::ContentAddressedStorage,
+    cas::{ContentAddressedStorage, LocalStorage},
+    database::Database,
     derivation::Derivation,
     diffoscope::Diffoscope,
-    eval::{eval, JobInstantiation},
-    messages::{BuildRequest, BuildStatus},
+    eval::{eval, EvalError, JobInstantiation},
+    messages::{Attr, BuildRequest, BuildStatus, EvaluationFailureV1, Subset, Variation},
 };
 
 use std::{
@@ -14,38 +15,57 @@ use std::{
     path::{Path, PathBuf},
 };
 
-pub fn report(instruction: BuildRequest) {
-    let job = match instruction {
-        BuildRequest::V1(ref req) => req.clone(),
-    };
+/// The NARs of unreproducible builds are read from `read_cas`, where
+/// `check` stored them. `nixpkgs_path` is the local checkout they were
+/// checked in, if any.
+pub fn report(
+    instruction: BuildRequest,
+    nixpkgs_path: Option<&Path>,
+    database: &Path,
+    legacy_logs: &Path,
+    read_cas: &dyn ContentAddressedStorage,
+) {
+    let report_dir = PathBuf::from("./report/");
+    fs::create_dir_all(&report_dir).unwrap();
+    let mut database = Database::open(database, legacy_logs).expect("Unable to open the results database");
 
     let JobInstantiation {
-        to_build, results, ..
-    } = eval(instruction.clone());
+        to_build,
+        results,
+        attribution,
+        ..
+    } = match eval(instruction.clone(), nixpkgs_path, &mut database) {
+        Ok(instantiation) => instantiation,
+        Err(e) => return report_evaluation_failure(&instruction, &report_dir, e),
+    };
 
-    let tmpdir = PathBuf::from("./tmp/");
-    let report_dir = PathBuf::from("./report/");
-    fs::create_dir_all(&report_dir).unwrap();
     let diff_dir = PathBuf::from("./report/diff");
     fs::create_dir_all(&diff_dir).unwrap();
     let mut html = File::create(report_dir.join("index.html")).unwrap();
 
-    let read_cas = ContentAddressedStorage::new(tmpdir.clone());
-    let write_cas = ContentAddressedStorage::new(report_dir.clone().join("cas"));
+    let write_cas = LocalStorage::new(report_dir.clone().join("cas"));
     let diffoscope = Diffoscope::new(write_cas.clone());
     let mut total = 0;
     let mut reproducible = 0;
     let mut unreproducible_list: Vec<String> = vec![];
     let mut unchecked_list: Vec<String> = vec![];
     let mut unchecked = 0;
+    let mut flaky = 0;
     let mut first_failed: Vec<String> = vec![];
 
-    let attr_name = job.subsets.values().next().unwrap().as_ref().unwrap().get(0).unwrap().join(".");
+    let attr_name = instruction
+        .subsets()
+        .values()
+        .flatten()
+        .flatten()
+        .map(|attr| attr.join("."))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let system = instruction.system().unwrap_or("x86_64-linux");
 
     for response in results.into_iter().filter(|response| {
-        (match response.request {
-            BuildRequest::V1(ref req) => req.nixpkgs_revision == job.nixpkgs_revision,
-        }) && to_build.contains(&PathBuf::from(&response.drv))
+        response.request.nixpkgs_revision() == instruction.nixpkgs_revision()
+            && to_build.contains(&PathBuf::from(&response.drv))
     }) {
         total += 1;
         match response.status {
@@ -59,42 +79,76 @@ pub fn report(instruction: BuildRequest) {
                 unchecked += 1;
                 unchecked_list.push(format!("<li><code>{}</code></li>", response.drv));
             }
-            BuildStatus::Unreproducible(hashes) => {
-                let parsed_drv = Derivation::parse(&Path::new(&response.drv)).unwrap();
+            BuildStatus::TimedOut => {
+                unchecked += 1;
+                unchecked_list.push(format!("<li><code>{}</code> (timed out)</li>", response.drv));
+            }
+            BuildStatus::Unreproducible(ref hashes) | BuildStatus::Flaky { ref hashes, .. } => {
+                let parsed_drv = Derivation::parse(Path::new(&response.drv)).unwrap();
+
+                let mut note = match response.status {
+                    BuildStatus::Flaky { rounds, disagreed, .. } => {
+                        flaky += 1;
+                        format!(" (flaky: {} of {} rounds differed)", disagreed, rounds)
+                    }
+                    _ => String::new(),
+                };
+                if let Some(origins) = attribution.get(Path::new(&response.drv)) {
+                    note.push_str(&format!(" (needed by {})", describe_origins(origins)));
+                }
+                if !response.variations.is_empty() {
+                    let variations: Vec<String> =
+                        response.variations.iter().map(describe_variation).collect();
+                    note.push_str(&format!(" (with {})", variations.join(", ")));
+                }
+                unreproducible_list.push(format!("<li><code>{}</code>{}</li>", response.drv, note));
+                for (output, output_hashes) in hashes.iter() {
+                    let hash_a = &output_hashes[0];
+                    for hash_b in output_hashes.iter().skip(1) {
+                        if let Some(output_path) = parsed_drv.outputs().get(output) {
+                            let dest_name = format!("{}-{}.html", hash_a, hash_b);
+                            let dest = diff_dir.join(&dest_name);
 
-                unreproducible_list.push(format!("<li><code>{}</code></li>", response.drv));
-                for (output, (hash_a, hash_b)) in hashes.iter() {
-                    if let Some(output_path) = parsed_drv.outputs().get(output) {
-                        let dest_name = format!("{}-{}.html", hash_a, hash_b);
-                        let dest = diff_dir.join(&dest_name);
+                            if dest.exists() {
+                                // ok
+                            } else {
+                                println!(
+                                    "Diffing {}'s {}: {} vs {}",
+                                    response.drv, output, hash_a, hash_b
+                                );
 
-                        if dest.exists() {
-                            // ok
+                                let (cas_a, cas_b) = match (
+                                    read_cas.str_to_id(hash_a),
+                                    read_cas.str_to_id(hash_b),
+                                ) {
+                                    (Ok(Some(cas_a)), Ok(Some(cas_b))) => (cas_a, cas_b),
+                                    (Err(e), _) | (_, Err(e)) => {
+                                        println!("Not diffing, looking up a NAR failed: {:?}", e);
+                                        continue;
+                                    }
+                                    _ => {
+                                        println!("Not diffing, a NAR is missing or corrupt");
+                                        continue;
+                                    }
+                                };
+                                let savedto = diffoscope
+                                    .nars(
+                                        &output_path.file_name().unwrap().to_string_lossy(),
+                                        &cas_a,
+                                        &cas_b,
+                                    )
+                                    .unwrap();
+                                println!("saved to: {}", savedto.display());
+                                fs::copy(savedto, dest).unwrap();
+                            }
+                            unreproducible_list.push(format!(
+                                "<li><a href=\"./diff/{}\">(diffoscope)</a> {}</li>",
+                                dest_name, output
+                            ));
                         } else {
-                            println!(
-                                "Diffing {}'s {}: {} vs {}",
-                                response.drv, output, hash_a, hash_b
-                            );
-
-                            let cas_a = read_cas.str_to_id(hash_a).unwrap();
-                            let cas_b = read_cas.str_to_id(hash_b).unwrap();
-                            let savedto = diffoscope
-                                .nars(
-                                    &output_path.file_name().unwrap().to_string_lossy(),
-                                    &cas_a.as_path_buf(),
-                                    &cas_b.as_path_buf(),
-                                )
-                                .unwrap();
-                            println!("saved to: {}", savedto.display());
-                            fs::copy(savedto, dest).unwrap();
+                            println!("Diffing {} but no output named {}", response.drv, output);
+                            // <li><a href="./diff/59nzffg69nprgg2zp8b36rqwha8vxzjk-perl-5.28.1.drv.html">(diffoscope)</a> <a href="./nix/store/59nzffg69nprgg2zp8b36rqwha8vxzjk-perl-5.28.1.drv">(drv)</a> <code>/nix/store/59nzffg69nprgg2zp8b36rqwha8vxzjk-perl-5.28.1.drv</code></li>
                         }
-                        unreproducible_list.push(format!(
-                            "<li><a href=\"./diff/{}\">(diffoscope)</a> {}</li>",
-                            dest_name, output
-                        ));
-                    } else {
-                        println!("Diffing {} but no output named {}", response.drv, output);
-                        // <li><a href="./diff/59nzffg69nprgg2zp8b36rqwha8vxzjk-perl-5.28.1.drv.html">(diffoscope)</a> <a href="./nix/store/59nzffg69nprgg2zp8b36rqwha8vxzjk-perl-5.28.1.drv">(drv)</a> <code>/nix/store/59nzffg69nprgg2zp8b36rqwha8vxzjk-perl-5.28.1.drv</code></li>
                     }
                 }
                 unreproducible_list.push("</ul></li>".to_string());
@@ -104,6 +158,27 @@ pub fn report(instruction: BuildRequest) {
         }
     }
 
+    // There is no percentage of nothing, and nothing checked is no
+    // success either
+    if total == 0 {
+        let stderr = if to_build.is_empty() {
+            "The evaluation yielded no derivations".to_string()
+        } else {
+            format!("None of the {} derivations evaluated have results", to_build.len())
+        };
+        let failures = instruction
+            .subsets()
+            .iter()
+            .map(|(subset, attrs)| EvaluationFailureV1 {
+                request: instruction.clone(),
+                subset: subset.clone(),
+                attrs: attrs.clone(),
+                stderr: stderr.clone(),
+            })
+            .collect();
+        return report_evaluation_failure(&instruction, &report_dir, EvalError { failures });
+    }
+
     if !first_failed.is_empty() {
         panic!("{} are unchecked:\n{:#?}", first_failed.len(), first_failed);
     }
@@ -115,7 +190,8 @@ pub fn report(instruction: BuildRequest) {
             unchecked = unchecked,
             total = total,
             percent = format!("{:.*}%", 2, 100.0 * (reproducible as f64 / total as f64)),
-            revision = job.nixpkgs_revision,
+            revision = instruction.nixpkgs_revision(),
+            system = system,
             now = Utc::now().to_string(),
             unreproduced_list = unreproducible_list.join("\n"),
             unchecked_list = unchecked_list.join("\n"),
@@ -143,16 +219,120 @@ r13y_paths_count {total}
 r13y_path_status_count{{status=\"reproducible\"}} {reproducible}
 r13y_path_status_count{{status=\"unreproducible\"}} {unreproducible}
 r13y_path_status_count{{status=\"unchecked\"}} {unchecked}
+r13y_path_status_count{{status=\"flaky\"}} {flaky}
+# HELP r13y_evaluation_failures Number of subsets which failed to evaluate
+# TYPE r13y_evaluation_failures gauge
+r13y_evaluation_failures 0
 
 ",
-            revision = job.nixpkgs_revision,
+            revision = instruction.nixpkgs_revision(),
             time = Utc::now().timestamp(),
             total = total,
             reproducible = reproducible,
             unreproducible = total - reproducible,
             unchecked = unchecked,
+            flaky = flaky,
+        ).as_bytes())
+        .unwrap();
+
+}
+
+fn report_evaluation_failure(instruction: &BuildRequest, report_dir: &Path, e: EvalError) {
+    let attr_name = instruction
+        .subsets()
+        .values()
+        .flatten()
+        .flatten()
+        .map(|attr| attr.join("."))
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    let failure_list: Vec<String> = e
+        .failures
+        .iter()
+        .map(|failure| {
+            let attrs = match failure.attrs {
+                Some(ref attrs) => attrs.iter().map(|attr| attr.join(".")).collect::<Vec<String>>().join(", "),
+                None => "every attribute".to_string(),
+            };
+            format!(
+                "<h3>{:?}: <code>{}</code></h3>\n<pre>{}</pre>",
+                failure.subset,
+                escape_html(&attrs),
+                escape_html(&failure.stderr)
+            )
+        })
+        .collect();
+
+    File::create(report_dir.join("index.html"))
+        .unwrap()
+        .write_all(
+            format!(
+                include_str!("./evaluation-failure.html"),
+                attr_name = escape_html(&attr_name),
+                system = instruction.system().unwrap_or("x86_64-linux"),
+                revision = instruction.nixpkgs_revision(),
+                failure_list = failure_list.join("\n"),
+                now = Utc::now().to_string(),
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+    File::create(report_dir.join("metrics"))
+        .unwrap()
+        .write_all(format!(
+"
+# HELP r13y_check_revision Check's nixpkgs revision
+# TYPE r13y_check_revision counter
+r13y_check_revision{{revision=\"{revision}\"}} 1
+# HELP r13y_check_time_seconds Time of the latest check
+# TYPE r13y_check_time_seconds counter
+r13y_check_time_seconds {time}
+# HELP r13y_evaluation_failures Number of subsets which failed to evaluate
+# TYPE r13y_evaluation_failures gauge
+r13y_evaluation_failures {failures}
+
+",
+            revision = instruction.nixpkgs_revision(),
+            time = Utc::now().timestamp(),
+            failures = e.failures.len(),
         ).as_bytes())
         .unwrap();
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
 
+/// Which subsets and attributes needed a derivation, shortened when
+/// it is needed by many of them.
+fn describe_origins(origins: &[(Subset, Attr)]) -> String {
+    const SHOWN: usize = 3;
 
+    let mut described: Vec<String> = origins
+        .iter()
+        .take(SHOWN)
+        .map(|(subset, attr)| {
+            if attr.is_empty() {
+                escape_html(&subset.to_string())
+            } else {
+                format!("<code>{}</code> of {}", escape_html(&attr.join(".")), escape_html(&subset.to_string()))
+            }
+        })
+        .collect();
+    if origins.len() > SHOWN {
+        described.push(format!("{} more", origins.len() - SHOWN));
+    }
+    described.join(", ")
+}
+
+fn describe_variation(variation: &Variation) -> String {
+    match variation {
+        Variation::Disorderfs => "disorderfs".to_string(),
+        Variation::Cores { cores } => format!("{} cores", cores),
+    }
 }
@@ -1,9 +1,13 @@
+use crate::nar;
+
 use std::{
-    io::{self, BufRead, Read},
+    io::{self, BufRead, PipeReader},
     path::{Path, PathBuf},
-    process::{Child, ChildStderr, ChildStdout, Command, Output, Stdio},
+    process::{Command, Output, Stdio},
+    thread,
 };
 
+#[derive(Default)]
 pub struct Store {}
 
 impl Store {
@@ -14,10 +18,10 @@ impl Store {
     pub fn create_gc_root(&self, store_path: &Path, gc_root: &Path) -> Result<(), RealiseError> {
         let realise = Command::new("nix-store")
             .arg("--add-root")
-            .arg(&gc_root)
+            .arg(gc_root)
             .arg("--indirect")
             .arg("--realise")
-            .arg(&store_path)
+            .arg(store_path)
             .stdin(Stdio::null())
             .output()?;
         if realise.status.success() {
@@ -43,29 +47,22 @@ impl Store {
         let line = lines.pop().expect("Just verified one line above")?;
 
         let path = PathBuf::from(line);
-        self.create_gc_root(&path, &gc_root)?;
+        self.create_gc_root(&path, gc_root)?;
         Ok(path)
     }
 
-    ///
+    /// Stream the NAR serialization of `path`, written by another
+    /// thread.
     pub fn export_nar(
         &self,
         path: &Path,
-    ) -> Result<(ChildStdout, ExportNarWait), ExportNarStartError> {
-        let mut add_cmd = Command::new("nix")
-            .arg("dump-path")
-            .arg(path)
-            .stdin(Stdio::null())
-            .stderr(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()?;
-        Ok((
-            add_cmd.stdout.take().unwrap(),
-            ExportNarWait {
-                stderr: add_cmd.stderr.take().unwrap(),
-                child: add_cmd,
-            },
-        ))
+    ) -> Result<(PipeReader, ExportNarWait), ExportNarStartError> {
+        let (reader, writer) = io::pipe()?;
+        let path = path.to_path_buf();
+        let thread = thread::Builder::new()
+            .name("export-nar".to_string())
+            .spawn(move || nar::dump(&path, writer))?;
+        Ok((reader, ExportNarWait { thread }))
     }
 
     fn debug_stderr(&self, stderr: Vec<u8>) {
@@ -114,20 +111,14 @@ impl From<io::Error> for ExportNarStartError {
 }
 
 pub struct ExportNarWait {
-    child: Child,
-    stderr: ChildStderr,
+    thread: thread::JoinHandle<io::Result<()>>,
 }
 
 impl ExportNarWait {
-    pub fn wait(&mut self) -> Result<(), ExportNarFinishError> {
-        let result = self.child.wait()?;
-
-        if result.success() {
-            Ok(())
-        } else {
-            let mut stderr = String::new();
-            self.stderr.read_to_string(&mut stderr)?;
-            Err(ExportNarFinishError::Failed(result.code(), stderr))
+    pub fn wait(self) -> Result<(), ExportNarFinishError> {
+        match self.thread.join() {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(ExportNarFinishError::Panicked),
         }
     }
 }
@@ -135,7 +126,7 @@ impl ExportNarWait {
 #[derive(Debug)]
 pub enum ExportNarFinishError {
     Io(io::Error),
-    Failed(Option<i32>, String),
+    Panicked,
 }
 impl From<io::Error> for ExportNarFinishError {
     fn from(e: io::Error) -> ExportNarFinishError {
@@ -0,0 +1,151 @@
+//! Upload NARs from the local CAS to the URLs in a BuildUploadTokens.
+//!
+//! Uploads are resumable: a `HEAD` of the upload URL may answer with
+//! an `Upload-Offset` header saying how many bytes the server already
+//! has, and we continue from there with a `Content-Range` PUT. Servers
+//! which don't know the header, like presigned S3 URLs, get the whole
+//! file.
+//!
+//! The content is hashed as it is read. If it doesn't match its id,
+//! the body is cut off before it ends so the server never sees a
+//! complete upload of the wrong content.
+
+use sha2::{Digest, Sha256};
+
+use crate::cas::{ContentAddressedStorage, ID};
+
+use std::io::{self, Read};
+
+const ATTEMPTS: u32 = 3;
+
+pub fn upload(
+    agent: &ureq::Agent,
+    cas: &dyn ContentAddressedStorage,
+    hash: &str,
+    url: &str,
+) -> Result<(), UploadError> {
+    let id = cas.str_to_id(hash)?.ok_or(UploadError::NotInStorage)?;
+    let total = id.content_length()?;
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let offset = remote_offset(agent, url);
+        if offset >= total && offset > 0 {
+            debug!("{} is already uploaded", hash);
+            return Ok(());
+        }
+
+        match put_from(agent, &id, url, offset, total) {
+            Ok(()) => return Ok(()),
+            Err(UploadError::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData => {
+                return Err(UploadError::HashMismatch)
+            }
+            Err(e) if attempt < ATTEMPTS => {
+                warn!("Uploading {} failed ({:?}), resuming", hash, e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Bytes the server already has, or 0 if it can't tell us.
+fn remote_offset(agent: &ureq::Agent, url: &str) -> u64 {
+    match agent.head(url).call() {
+        Ok(response) => response
+            .header("Upload-Offset")
+            .and_then(|offset| offset.parse().ok())
+            .unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+fn put_from(
+    agent: &ureq::Agent,
+    id: &ID,
+    url: &str,
+    offset: u64,
+    total: u64,
+) -> Result<(), UploadError> {
+    let hash = id.id();
+    let mut reader = HashingReader {
+        inner: id.open()?,
+        digest: Sha256::new(),
+        expected: hash.to_string(),
+    };
+
+    // The skipped prefix still has to go through the hash
+    io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+
+    info!("Uploading {} from byte {} of {}", hash, offset, total);
+    // Coordinators refuse bodies of undeclared length
+    let mut request = agent
+        .put(url)
+        .set("Content-Length", &(total - offset).to_string());
+    if offset > 0 {
+        request = request.set(
+            "Content-Range",
+            &format!("bytes {}-{}/{}", offset, total.saturating_sub(1), total),
+        );
+    }
+
+    match request.send(reader) {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Transport(transport)) => match io_cause(&transport) {
+            Some(kind) => Err(UploadError::Io(io::Error::new(kind, transport.to_string()))),
+            None => Err(UploadError::Http(Box::new(ureq::Error::Transport(transport)))),
+        },
+        Err(e) => Err(UploadError::Http(Box::new(e))),
+    }
+}
+
+/// Surface our own InvalidData error from inside ureq's error.
+fn io_cause(transport: &ureq::Transport) -> Option<io::ErrorKind> {
+    let mut source = std::error::Error::source(transport);
+    while let Some(error) = source {
+        if let Some(io) = error.downcast_ref::<io::Error>() {
+            if io.kind() == io::ErrorKind::InvalidData {
+                return Some(io.kind());
+            }
+        }
+        source = error.source();
+    }
+    None
+}
+
+struct HashingReader<R> {
+    inner: R,
+    digest: Sha256,
+    expected: String,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if len == 0 {
+            let actual = format!("{:x}", self.digest.clone().result());
+            if actual != self.expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("stored as {} but hashes to {}", self.expected, actual),
+                ));
+            }
+        }
+        self.digest.input(&buf[..len]);
+        Ok(len)
+    }
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    Io(io::Error),
+    Http(Box<ureq::Error>),
+    NotInStorage,
+    HashMismatch,
+}
+
+impl From<io::Error> for UploadError {
+    fn from(e: io::Error) -> Self {
+        UploadError::Io(e)
+    }
+}
@@ -0,0 +1,769 @@
+//! Verifier mode: fetch the build request from a coordination server,
+//! check it like `check` does, and POST each result back to the
+//! request's `result_url` as soon as it is known.
+//!
+//! Results are signed and written to an outbox file before they are
+//! sent, so nothing is lost while the server is unreachable; the
+//! outbox is drained with backoff, and again on the next start. The
+//! NAR uploads the server asks for are queued the same way, in
+//! `<outbox>.uploads.jsonl`.
+
+use log::{debug, info, warn};
+
+mod outbox;
+use outbox::Outbox;
+
+use ed25519_dalek::{SigningKey, VerifyingKey};
+
+use crate::{
+    cas::ContentAddressedStorage,
+    check::{check, CheckError, CheckOptions},
+    eval::EvalError,
+    messages::{
+        BuildRequest, BuildResponse, BuildResponseV2, BuildUploadTokens,
+        SignatureError, Signed,
+    },
+    upload::{upload, UploadError},
+};
+
+use std::{
+    cmp,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Give up on fetching the request after this many failures
+const FETCH_ATTEMPTS: u32 = 8;
+/// Once checking is finished, try draining the outbox this many times
+/// before leaving the rest for the next run
+const FINAL_DELIVERY_ATTEMPTS: u32 = 8;
+/// Once checking was interrupted, try draining the outbox for this
+/// long. Spot instances are often gone soon after the signal.
+const SHUTDOWN_DELIVERY_TIME: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+pub struct VerifierConfig {
+    /// Base URL of the coordination server
+    pub coordinator: String,
+    /// Keys the build request may be signed with
+    pub coordinator_keys: Vec<VerifyingKey>,
+    /// Key to sign our responses with
+    pub signing_key: SigningKey,
+    /// File undelivered responses are kept in
+    pub outbox: PathBuf,
+    pub check: CheckOptions,
+}
+
+pub fn verify(config: VerifierConfig) -> Result<(), VerifyError> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(30))
+        .timeout_read(Duration::from_secs(300))
+        .build();
+
+    let instruction = fetch_request(&agent, &config.coordinator, &config.coordinator_keys)?;
+    debug!("Using instruction: {:#?}", instruction);
+
+    let result_url = instruction.result_url().to_string();
+
+    let poster = Poster {
+        agent,
+        cas: config.check.cas.clone(),
+        result_url,
+        signing_key: config.signing_key,
+        coordinator_keys: config.coordinator_keys,
+        rejected: config.outbox.with_extension("rejected"),
+        uploads: Outbox::open(config.outbox.with_extension("uploads.jsonl"))?,
+        outbox: Outbox::open(config.outbox)?,
+        shutdown_delivery_time: SHUTDOWN_DELIVERY_TIME,
+        deadline: None,
+    };
+
+    let (checked_tx, checked_rx) = channel();
+    let poster = thread::Builder::new()
+        .name("poster".to_string())
+        .spawn(move || poster.run(checked_rx))
+        .unwrap();
+
+    let responses = checked_tx.clone();
+    let checked = check(
+        instruction,
+        &config.check,
+        move |response| responses.send(Checked::Response(Box::new(response.clone()))).unwrap(),
+    );
+    // The poster may have stopped on an error already, joining it
+    // reports that
+    let _ = checked_tx.send(Checked::Finished {
+        interrupted: matches!(checked, Err(CheckError::Interrupted)),
+    });
+
+    // Deliver the evaluation failure, or what was checked before
+    // being interrupted, before reporting it
+    poster.join().unwrap()?;
+    Ok(checked?)
+}
+
+fn fetch_request(
+    agent: &ureq::Agent,
+    coordinator: &str,
+    coordinator_keys: &[VerifyingKey],
+) -> Result<BuildRequest, VerifyError> {
+    let url = format!("{}/latest", coordinator.trim_end_matches('/'));
+
+    let mut attempt = 0;
+    let body = loop {
+        attempt += 1;
+        match agent.get(&url).call() {
+            Ok(response) => break response.into_string()?,
+            Err(e) if attempt < FETCH_ATTEMPTS => {
+                let delay = backoff(attempt);
+                warn!("Fetching {} failed ({}), retrying in {:?}", url, e, delay);
+                thread::sleep(delay);
+            }
+            Err(e) => return Err(VerifyError::Http(Box::new(e))),
+        }
+    };
+
+    let signed: Signed<BuildRequest> = serde_json::from_str(&body)?;
+    Ok(signed.verify(coordinator_keys)?)
+}
+
+fn backoff(failures: u32) -> Duration {
+    cmp::min(Duration::from_secs(1 << cmp::min(failures, 16)), MAX_BACKOFF)
+}
+
+struct Poster {
+    agent: ureq::Agent,
+    cas: Arc<dyn ContentAddressedStorage>,
+    result_url: String,
+    signing_key: SigningKey,
+    coordinator_keys: Vec<VerifyingKey>,
+    outbox: Outbox,
+    rejected: PathBuf,
+    /// `PendingUpload`s, in the order the server asked for them
+    uploads: Outbox,
+    shutdown_delivery_time: Duration,
+    /// When to stop delivering, once checking was interrupted
+    deadline: Option<Instant>,
+}
+
+/// What `check` passes on to the poster
+enum Checked {
+    Response(Box<BuildResponse>),
+    /// Nothing more will be checked
+    Finished { interrupted: bool },
+}
+
+/// A NAR the server gave us an upload token for
+#[derive(Serialize, Deserialize)]
+struct PendingUpload {
+    sha256: String,
+    url: String,
+}
+
+enum Delivery {
+    Accepted(Option<String>),
+    Rejected(u16, String),
+}
+
+impl Poster {
+    fn run(mut self, checked: Receiver<Checked>) -> Result<(), VerifyError> {
+        let mut failures = 0;
+        let mut next_attempt = Instant::now();
+        let mut finished = false;
+        let mut final_attempts = 0;
+
+        loop {
+            // Uploads can be big, when stopping they wait for the next run
+            if self.outbox.is_empty() && (self.uploads.is_empty() || self.deadline.is_some()) {
+                if finished {
+                    if !self.uploads.is_empty() {
+                        info!("Leaving {} uploads for the next run", self.uploads.len());
+                    }
+                    return Ok(());
+                }
+                match checked.recv() {
+                    Ok(message) => finished = self.receive(message)?,
+                    Err(_) => finished = true,
+                }
+                continue;
+            }
+
+            let wait = next_attempt.saturating_duration_since(Instant::now());
+            if !finished {
+                match checked.recv_timeout(wait) {
+                    Ok(message) => {
+                        finished = self.receive(message)?;
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => finished = true,
+                }
+            } else if let Some(deadline) = self.deadline {
+                if deadline.saturating_duration_since(Instant::now()) <= wait {
+                    warn!(
+                        "Out of time, {} responses and {} uploads remain in the outbox",
+                        self.outbox.len(),
+                        self.uploads.len()
+                    );
+                    return Ok(());
+                }
+                thread::sleep(wait);
+            } else if final_attempts == FINAL_DELIVERY_ATTEMPTS {
+                warn!(
+                    "Giving up for now, {} responses and {} uploads remain in the outbox",
+                    self.outbox.len(),
+                    self.uploads.len()
+                );
+                return Ok(());
+            } else {
+                final_attempts += 1;
+                thread::sleep(wait);
+            }
+
+            match self.deliver() {
+                Ok(()) => {
+                    failures = 0;
+                    next_attempt = Instant::now();
+                }
+                Err(e) => {
+                    failures += 1;
+                    let delay = backoff(failures);
+                    warn!(
+                        "Delivering to {} failed ({:?}), {} responses and {} uploads buffered, retrying in {:?}",
+                        self.result_url,
+                        e,
+                        self.outbox.len(),
+                        self.uploads.len(),
+                        delay
+                    );
+                    next_attempt = Instant::now() + delay;
+                }
+            }
+        }
+    }
+
+    /// Returns whether checking is finished
+    fn receive(&mut self, checked: Checked) -> Result<bool, VerifyError> {
+        match checked {
+            Checked::Response(response) => {
+                self.enqueue(*response)?;
+                Ok(false)
+            }
+            Checked::Finished { interrupted } => {
+                if interrupted {
+                    info!("Delivering for up to {:?} before stopping", self.shutdown_delivery_time);
+                    self.deadline = Some(Instant::now() + self.shutdown_delivery_time);
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    fn enqueue(&mut self, response: BuildResponse) -> Result<(), VerifyError> {
+        let signed = Signed::sign(&response, &self.signing_key)?;
+        self.outbox.push(serde_json::to_string(&signed)?)?;
+        Ok(())
+    }
+
+    /// Send everything in the outbox, in order, and then the uploads
+    /// the server asked for, until both are empty or the server can't
+    /// be reached. Once stopping, only the outbox.
+    fn deliver(&mut self) -> Result<(), VerifyError> {
+        while let Some(signed) = self.outbox.front() {
+            let signed = signed.to_string();
+            match self.post(&signed)? {
+                Delivery::Accepted(tokens) => {
+                    if let Some(tokens) = tokens {
+                        self.handle_upload_tokens(&signed, &tokens)?;
+                    }
+                }
+                Delivery::Rejected(status, reason) => {
+                    warn!("Response rejected with {}: {}", status, reason);
+                    let mut rejected = OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&self.rejected)?;
+                    rejected.write_all(format!("{}\n", signed).as_bytes())?;
+                }
+            }
+            self.outbox.pop_front()?;
+        }
+
+        if self.deadline.is_some() {
+            return Ok(());
+        }
+        self.upload_pending()
+    }
+
+    fn post(&self, signed: &str) -> Result<Delivery, VerifyError> {
+        let mut request = self
+            .agent
+            .post(&self.result_url)
+            .set("Content-Type", "application/json");
+        if let Some(deadline) = self.deadline {
+            request = request.timeout(deadline.saturating_duration_since(Instant::now()));
+        }
+        let response = request.send_string(signed);
+
+        match response {
+            Ok(response) if response.status() == 200 => {
+                Ok(Delivery::Accepted(Some(response.into_string()?)))
+            }
+            Ok(_) => Ok(Delivery::Accepted(None)),
+            // Retrying won't change the server's mind about a 4xx
+            Err(ureq::Error::Status(status, response)) if (400..500).contains(&status) => Ok(
+                Delivery::Rejected(status, response.into_string().unwrap_or_default()),
+            ),
+            Err(e) => Err(VerifyError::Http(Box::new(e))),
+        }
+    }
+
+    /// Queue uploads of the NARs the coordinator asked for after we
+    /// reported `signed` as unreproducible.
+    fn handle_upload_tokens(&mut self, signed: &str, body: &str) -> Result<(), VerifyError> {
+        let tokens = serde_json::from_str::<Signed<BuildUploadTokens>>(body)
+            .map_err(SignatureError::from)
+            .and_then(|signed| signed.verify(&self.coordinator_keys));
+        let tokens = match tokens {
+            Ok(BuildUploadTokens::V1(tokens)) => tokens,
+            Err(e) => {
+                warn!("Ignoring invalid upload tokens: {:?}", e);
+                return Ok(());
+            }
+        };
+
+        let ours = serde_json::from_str::<Signed<BuildResponse>>(signed)
+            .map_err(SignatureError::from)
+            .and_then(|signed| signed.verify(&[self.signing_key.verifying_key()]));
+        let produced: Vec<String> = match ours {
+            Ok(ref response) => match response.result() {
+                Some(BuildResponseV2 { status, .. }) => status
+                    .hashes()
+                    .into_iter()
+                    .flat_map(|hashes| hashes.values().flatten().cloned())
+                    .collect(),
+                None => vec![],
+            },
+            Err(_) => vec![],
+        };
+
+        for (hash, url) in tokens.iter() {
+            if !produced.contains(hash) {
+                warn!("Not uploading {}, it isn't from the reported build", hash);
+                continue;
+            }
+
+            let pending = PendingUpload {
+                sha256: hash.clone(),
+                url: url.clone(),
+            };
+            self.uploads.push(serde_json::to_string(&pending)?)?;
+        }
+        Ok(())
+    }
+
+    /// Upload everything queued, in order, until the queue is empty
+    /// or an upload fails in a way which is worth retrying.
+    fn upload_pending(&mut self) -> Result<(), VerifyError> {
+        while let Some(pending) = self.uploads.front() {
+            match serde_json::from_str::<PendingUpload>(pending) {
+                Ok(PendingUpload { sha256, url }) => {
+                    match upload(&self.agent, &*self.cas, &sha256, &url) {
+                        Ok(()) => info!("Uploaded {}", sha256),
+                        // Retrying won't change these
+                        Err(e @ UploadError::NotInStorage) | Err(e @ UploadError::HashMismatch) => {
+                            warn!("Not uploading {}: {:?}", sha256, e)
+                        }
+                        Err(UploadError::Http(e)) if is_refusal(&e) => {
+                            warn!("Upload of {} was refused: {}", sha256, e)
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                Err(e) => warn!("Dropping malformed upload {:?}: {}", pending, e),
+            }
+            self.uploads.pop_front()?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the server refused an upload for good. A 409 means another
+/// upload of the same NAR is in progress, so it is worth retrying.
+fn is_refusal(e: &ureq::Error) -> bool {
+    match e {
+        ureq::Error::Status(status, _) => (400..500).contains(status) && *status != 409,
+        ureq::Error::Transport(_) => false,
+    }
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    Io(io::Error),
+    Http(Box<ureq::Error>),
+    Signature(SignatureError),
+    Json(serde_json::Error),
+    Eval(EvalError),
+    Upload(UploadError),
+    Interrupted,
+}
+
+impl From<io::Error> for VerifyError {
+    fn from(e: io::Error) -> Self {
+        VerifyError::Io(e)
+    }
+}
+
+impl From<SignatureError> for VerifyError {
+    fn from(e: SignatureError) -> Self {
+        VerifyError::Signature(e)
+    }
+}
+
+impl From<serde_json::Error> for VerifyError {
+    fn from(e: serde_json::Error) -> Self {
+        VerifyError::Json(e)
+    }
+}
+
+impl From<UploadError> for VerifyError {
+    fn from(e: UploadError) -> Self {
+        VerifyError::Upload(e)
+    }
+}
+
+impl From<EvalError> for VerifyError {
+    fn from(e: EvalError) -> Self {
+        VerifyError::Eval(e)
+    }
+}
+
+impl From<CheckError> for VerifyError {
+    fn from(e: CheckError) -> Self {
+        match e {
+            CheckError::Eval(e) => VerifyError::Eval(e),
+            CheckError::Interrupted => VerifyError::Interrupted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cas::{ContentAddressedStorage, LocalStorage},
+        coordinator::{self, CoordinatorConfig},
+        keys::generate_signing_key,
+        messages::{BuildRequestV2, BuildStatus, Hashes, Subset},
+    };
+
+    use std::{
+        fs,
+        io::Read,
+        net::{TcpListener, TcpStream},
+        path::Path,
+    };
+
+    use tempdir::TempDir;
+
+    /// A coordination server and a verifier talking to it over
+    /// localhost
+    struct Setup {
+        coordinator_url: String,
+        coordinator_key: SigningKey,
+        verifier_key: SigningKey,
+        state_dir: TempDir,
+        work_dir: TempDir,
+    }
+
+    impl Setup {
+        fn new() -> Setup {
+            Setup {
+                coordinator_url: free_url(),
+                coordinator_key: generate_signing_key(),
+                verifier_key: generate_signing_key(),
+                state_dir: TempDir::new("coordinator").unwrap(),
+                work_dir: TempDir::new("verifier").unwrap(),
+            }
+        }
+
+        fn request(&self, revision: &str) -> BuildRequest {
+            BuildRequest::V2(BuildRequestV2 {
+                request_id: format!("{}-test", revision),
+                nixpkgs_revision: revision.to_string(),
+                nixpkgs_sha256sum: String::new(),
+                result_url: format!("{}/result", self.coordinator_url),
+                subsets: vec![(Subset::Nixpkgs, None)].into_iter().collect(),
+                system: "x86_64-linux".to_string(),
+                deadline: None,
+                variations: vec![],
+                overlays: vec![],
+            })
+        }
+
+        /// Serve `instruction` until the tests exit
+        fn serve(&self, instruction: BuildRequest) {
+            self.serve_at(&self.coordinator_url, &self.coordinator_url, instruction)
+        }
+
+        /// Serve `instruction` at `url` until the tests exit, handing
+        /// out upload URLs below `public_url`
+        fn serve_at(&self, url: &str, public_url: &str, instruction: BuildRequest) {
+            let listen = url.trim_start_matches("http://").to_string();
+            let config = CoordinatorConfig {
+                listen: listen.clone(),
+                public_url: public_url.to_string(),
+                signing_key: self.coordinator_key.clone(),
+                trusted_keys: vec![self.verifier_key.verifying_key()],
+                state_dir: self.state_dir.path().to_path_buf(),
+                threads: 2,
+                max_upload_bytes: 1024,
+            };
+            thread::spawn(move || coordinator::serve(instruction, config).unwrap());
+            while TcpStream::connect(&listen).is_err() {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        fn cas(&self) -> LocalStorage {
+            LocalStorage::new(self.work_dir.path().join("cas"))
+        }
+
+        /// A poster the coordinator issued an upload token for `hash`
+        /// to, without uploading anything yet
+        fn issue_token(&self, instruction: &BuildRequest, hash: &str) -> Poster {
+            let mut hashes = Hashes::new();
+            hashes.insert("out".to_string(), vec![hash.to_string()]);
+            let mut poster = self.poster(instruction);
+            poster
+                .enqueue(response(instruction.clone(), BuildStatus::Unreproducible(hashes)))
+                .unwrap();
+            let signed = poster.outbox.front().unwrap().to_string();
+            let tokens = match poster.post(&signed).unwrap() {
+                Delivery::Accepted(Some(tokens)) => tokens,
+                _ => panic!("expected upload tokens"),
+            };
+            poster.handle_upload_tokens(&signed, &tokens).unwrap();
+            poster
+        }
+
+        fn poster(&self, instruction: &BuildRequest) -> Poster {
+            let outbox = self.work_dir.path().join("outbox.jsonl");
+            Poster {
+                agent: ureq::agent(),
+                cas: Arc::new(self.cas()),
+                result_url: instruction.result_url().to_string(),
+                signing_key: self.verifier_key.clone(),
+                coordinator_keys: vec![self.coordinator_key.verifying_key()],
+                rejected: outbox.with_extension("rejected"),
+                uploads: Outbox::open(outbox.with_extension("uploads.jsonl")).unwrap(),
+                outbox: Outbox::open(outbox).unwrap(),
+                shutdown_delivery_time: SHUTDOWN_DELIVERY_TIME,
+                deadline: None,
+            }
+        }
+    }
+
+    /// A URL nothing listens at yet
+    fn free_url() -> String {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        format!("http://{}", address)
+    }
+
+    fn response(request: BuildRequest, status: BuildStatus) -> BuildResponse {
+        BuildResponse::V2(BuildResponseV2 {
+            request,
+            drv: "/nix/store/00000000000000000000000000000000-hello.drv".to_string(),
+            status,
+            variations: vec![],
+        })
+    }
+
+    fn lines(path: &Path) -> usize {
+        fs::read_to_string(path).map_or(0, |contents| contents.lines().count())
+    }
+
+    #[test]
+    fn responses_to_the_published_request_are_recorded() {
+        let setup = Setup::new();
+        setup.serve(setup.request("v1"));
+
+        let agent = ureq::agent();
+        let coordinator_keys = [setup.coordinator_key.verifying_key()];
+        let instruction = fetch_request(&agent, &setup.coordinator_url, &coordinator_keys).unwrap();
+        assert_eq!(instruction.nixpkgs_revision(), "v1");
+
+        let mut poster = setup.poster(&instruction);
+        poster
+            .enqueue(response(instruction.clone(), BuildStatus::Reproducible))
+            .unwrap();
+        // Validly signed, but for a request the coordinator never published
+        poster
+            .enqueue(response(setup.request("v2"), BuildStatus::Reproducible))
+            .unwrap();
+        poster.deliver().unwrap();
+
+        assert!(poster.outbox.is_empty());
+        assert_eq!(lines(&setup.state_dir.path().join("responses-v1.jsonl")), 1);
+        assert!(!setup.state_dir.path().join("responses-v2.jsonl").exists());
+        assert_eq!(lines(&poster.rejected), 1);
+    }
+
+    #[test]
+    fn responses_from_untrusted_verifiers_are_rejected() {
+        let setup = Setup::new();
+        let instruction = setup.request("v1");
+        setup.serve(instruction.clone());
+
+        let mut poster = setup.poster(&instruction);
+        poster.signing_key = generate_signing_key();
+        poster
+            .enqueue(response(instruction, BuildStatus::Reproducible))
+            .unwrap();
+        poster.deliver().unwrap();
+
+        assert!(!setup.state_dir.path().join("responses-v1.jsonl").exists());
+        assert_eq!(lines(&poster.rejected), 1);
+    }
+
+    #[test]
+    fn uploads_resume_after_both_sides_restart() {
+        let setup = Setup::new();
+        let instruction = setup.request("v1");
+        let cas = setup.cas();
+        let first = cas.store_from(&mut &b"first build"[..]).unwrap();
+        let second = cas.store_from(&mut &b"second build"[..]).unwrap();
+        let mut hashes = Hashes::new();
+        hashes.insert(
+            "out".to_string(),
+            vec![first.id().to_string(), second.id().to_string()],
+        );
+
+        // Upload URLs point at an address nothing listens at yet
+        let upload_url = free_url();
+        setup.serve_at(&setup.coordinator_url, &upload_url, instruction.clone());
+
+        let mut poster = setup.poster(&instruction);
+        poster
+            .enqueue(response(instruction.clone(), BuildStatus::Unreproducible(hashes)))
+            .unwrap();
+        assert!(poster.deliver().is_err());
+        assert!(poster.outbox.is_empty());
+        assert_eq!(poster.uploads.len(), 2);
+        drop(poster);
+
+        // The coordinator comes back where the upload URLs point, and
+        // the verifier starts again
+        setup.serve_at(&upload_url, &upload_url, instruction.clone());
+        let mut poster = setup.poster(&instruction);
+        assert_eq!(poster.uploads.len(), 2);
+        poster.deliver().unwrap();
+        assert!(poster.uploads.is_empty());
+
+        let received = LocalStorage::new(setup.state_dir.path().join("cas"));
+        for id in [first, second].iter() {
+            assert!(received.str_to_id(id.id()).unwrap().unwrap().verify().unwrap());
+        }
+    }
+
+    #[test]
+    fn uploads_without_a_token_are_refused() {
+        let setup = Setup::new();
+        setup.serve(setup.request("v1"));
+
+        let hash = "0".repeat(64);
+        let refused = ureq::put(&format!("{}/upload/{}", setup.coordinator_url, hash))
+            .send_bytes(b"anything");
+        assert!(matches!(refused, Err(ureq::Error::Status(403, _))));
+    }
+
+    #[test]
+    fn uploads_of_undeclared_or_excessive_length_are_refused() {
+        let setup = Setup::new();
+        let instruction = setup.request("v1");
+        setup.serve(instruction.clone());
+        let hash = "0".repeat(64);
+        setup.issue_token(&instruction, &hash);
+        let url = format!("{}/upload/{}", setup.coordinator_url, hash);
+
+        // Chunked, so nothing bounds how much is sent
+        let chunked = ureq::put(&url).send(&b"anything"[..]);
+        assert!(matches!(chunked, Err(ureq::Error::Status(411, _))));
+
+        // The tests' coordinators take up to 1 KiB
+        let too_large = ureq::put(&url).send_bytes(&[0; 2048]);
+        assert!(matches!(too_large, Err(ureq::Error::Status(413, _))));
+        let too_large = ureq::put(&url)
+            .set("Content-Range", "bytes 0-2047/2048")
+            .send_bytes(&[0; 8]);
+        assert!(matches!(too_large, Err(ureq::Error::Status(413, _))));
+        assert_eq!(ureq::head(&url).call().unwrap().header("Upload-Offset"), Some("0"));
+    }
+
+    #[test]
+    fn partial_uploads_are_resumed() {
+        let setup = Setup::new();
+        let instruction = setup.request("v1");
+        setup.serve(instruction.clone());
+        let content = b"0123456789abcdef";
+        let id = setup.cas().store_from(&mut &content[..]).unwrap();
+        let mut poster = setup.issue_token(&instruction, id.id());
+
+        // An earlier attempt got the first half across
+        let url = format!("{}/upload/{}", setup.coordinator_url, id.id());
+        let partial = ureq::put(&url)
+            .set("Content-Range", "bytes 0-15/16")
+            .send_bytes(&content[..8])
+            .unwrap();
+        assert_eq!(partial.status(), 202);
+        assert_eq!(ureq::head(&url).call().unwrap().header("Upload-Offset"), Some("8"));
+
+        poster.upload_pending().unwrap();
+        let received = LocalStorage::new(setup.state_dir.path().join("cas"));
+        let mut stored = Vec::new();
+        received
+            .str_to_id(id.id())
+            .unwrap()
+            .unwrap()
+            .open()
+            .unwrap()
+            .read_to_end(&mut stored)
+            .unwrap();
+        assert_eq!(stored, &content[..]);
+    }
+
+    #[test]
+    fn delivery_stops_soon_after_an_interruption() {
+        let setup = Setup::new();
+        let instruction = setup.request("v1");
+        // Accepts connections, but never answers
+        let listener = TcpListener::bind(setup.coordinator_url.trim_start_matches("http://")).unwrap();
+
+        let mut poster = setup.poster(&instruction);
+        poster.shutdown_delivery_time = Duration::from_secs(1);
+        let (checked_tx, checked_rx) = channel();
+        checked_tx
+            .send(Checked::Response(Box::new(response(instruction.clone(), BuildStatus::Reproducible))))
+            .unwrap();
+        checked_tx.send(Checked::Finished { interrupted: true }).unwrap();
+
+        let started = Instant::now();
+        poster.run(checked_rx).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5), "took {:?}", started.elapsed());
+        drop(listener);
+
+        // Left for the next run
+        let poster = setup.poster(&instruction);
+        assert_eq!(poster.outbox.len(), 1);
+    }
+}
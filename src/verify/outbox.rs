@@ -0,0 +1,237 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Signed responses which haven't been accepted by the coordination
+/// server yet, persisted one per line so they survive restarts.
+///
+/// The file is only appended to. How many of its bytes were delivered
+/// is kept next to it, in `<outbox>.delivered`, and the delivered
+/// lines are only dropped from the file once they outweigh the pending
+/// ones, so draining a long outbox doesn't rewrite it every time.
+pub struct Outbox {
+    path: PathBuf,
+    delivered_path: PathBuf,
+    /// Bytes at the start of the file which were delivered
+    delivered: u64,
+    /// Bytes in the file
+    length: u64,
+    /// Each pending response, with the bytes of its line
+    pending: VecDeque<(String, u64)>,
+}
+
+impl Outbox {
+    pub fn open(path: PathBuf) -> Result<Outbox, io::Error> {
+        let delivered_path = path.with_extension("delivered");
+        let mut outbox = Outbox {
+            delivered: read_delivered(&delivered_path)?,
+            delivered_path,
+            path,
+            length: 0,
+            pending: VecDeque::new(),
+        };
+
+        let mut file = match File::open(&outbox.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                outbox.delivered = 0;
+                return Ok(outbox);
+            }
+            Err(e) => return Err(e),
+        };
+        outbox.length = file.metadata()?.len();
+        if outbox.delivered > outbox.length {
+            warn!("{:?} doesn't match the outbox, resending everything", outbox.delivered_path);
+            outbox.delivered = 0;
+        }
+        file.seek(SeekFrom::Start(outbox.delivered))?;
+
+        let mut needs_compacting = false;
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 {
+            let entry = line.trim();
+            // A crash mid-append leaves a partial last line
+            if !entry.is_empty() && serde_json::from_str::<serde_json::Value>(entry).is_ok() {
+                outbox.pending.push_back((entry.to_string(), line.len() as u64));
+            } else {
+                if !entry.is_empty() {
+                    warn!("Dropping malformed outbox entry: {:?}", entry);
+                }
+                needs_compacting = true;
+            }
+            line.clear();
+        }
+
+        if needs_compacting {
+            outbox.compact()?;
+        }
+        if !outbox.pending.is_empty() {
+            info!("Resuming with {} undelivered responses", outbox.pending.len());
+        }
+
+        Ok(outbox)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn front(&self) -> Option<&str> {
+        self.pending.front().map(|(signed, _)| signed.as_str())
+    }
+
+    pub fn push(&mut self, signed: String) -> Result<(), io::Error> {
+        let line = format!("{}\n", signed);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+
+        self.length += line.len() as u64;
+        self.pending.push_back((signed, line.len() as u64));
+        Ok(())
+    }
+
+    pub fn pop_front(&mut self) -> Result<Option<String>, io::Error> {
+        let (popped, bytes) = match self.pending.pop_front() {
+            Some(popped) => popped,
+            None => return Ok(None),
+        };
+        self.delivered += bytes;
+
+        if self.delivered >= self.length - self.delivered {
+            self.compact()?;
+        } else {
+            replace(&self.delivered_path, format!("{}\n", self.delivered).as_bytes())?;
+        }
+        Ok(Some(popped))
+    }
+
+    /// Rewrite the outbox file with only what is still pending.
+    ///
+    /// The delivered offset is reset before the new file replaces the
+    /// old one, so a crash in between sends some responses twice
+    /// rather than skipping any.
+    fn compact(&mut self) -> Result<(), io::Error> {
+        let contents: String = self
+            .pending
+            .iter()
+            .map(|(signed, _)| format!("{}\n", signed))
+            .collect();
+        let tmp = write_tmp(&self.path, contents.as_bytes())?;
+        replace(&self.delivered_path, b"0\n")?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.delivered = 0;
+        self.length = contents.len() as u64;
+        for (signed, bytes) in self.pending.iter_mut() {
+            *bytes = signed.len() as u64 + 1;
+        }
+        Ok(())
+    }
+}
+
+fn read_delivered(path: &Path) -> Result<u64, io::Error> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents.trim().parse().unwrap_or(0)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// Write `contents` next to `path`, synced, and return where
+fn write_tmp(path: &Path, contents: &[u8]) -> Result<PathBuf, io::Error> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_data()?;
+    Ok(tmp)
+}
+
+/// Replace `path` with `contents` in one rename.
+fn replace(path: &Path, contents: &[u8]) -> Result<(), io::Error> {
+    let tmp = write_tmp(path, contents)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    fn entry(i: usize) -> String {
+        format!("{{\"entry\":{}}}", i)
+    }
+
+    #[test]
+    fn delivered_responses_stay_delivered_across_restarts() {
+        let dir = TempDir::new("outbox").unwrap();
+        let path = dir.path().join("outbox.jsonl");
+
+        let mut outbox = Outbox::open(path.clone()).unwrap();
+        for i in 0..5 {
+            outbox.push(entry(i)).unwrap();
+        }
+        assert_eq!(outbox.pop_front().unwrap(), Some(entry(0)));
+
+        let mut outbox = Outbox::open(path.clone()).unwrap();
+        assert_eq!(outbox.len(), 4);
+        assert_eq!(outbox.front(), Some(entry(1).as_str()));
+
+        while outbox.pop_front().unwrap().is_some() {}
+        assert!(Outbox::open(path.clone()).unwrap().is_empty());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn delivering_doesnt_rewrite_the_file_every_time() {
+        let dir = TempDir::new("outbox").unwrap();
+        let path = dir.path().join("outbox.jsonl");
+
+        let mut outbox = Outbox::open(path.clone()).unwrap();
+        for i in 0..10 {
+            outbox.push(entry(i)).unwrap();
+        }
+        let length = fs::metadata(&path).unwrap().len();
+        for _ in 0..4 {
+            outbox.pop_front().unwrap();
+        }
+        assert_eq!(fs::metadata(&path).unwrap().len(), length);
+
+        // Once more is delivered than pending, the file is compacted
+        outbox.pop_front().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < length);
+
+        let mut outbox = Outbox::open(path).unwrap();
+        assert_eq!(outbox.len(), 5);
+        assert_eq!(outbox.pop_front().unwrap(), Some(entry(5)));
+    }
+
+    #[test]
+    fn a_partial_last_line_is_dropped() {
+        let dir = TempDir::new("outbox").unwrap();
+        let path = dir.path().join("outbox.jsonl");
+        fs::write(&path, format!("{}\n{}\n{{\"entr", entry(0), entry(1))).unwrap();
+
+        let mut outbox = Outbox::open(path.clone()).unwrap();
+        assert_eq!(outbox.len(), 2);
+        outbox.push(entry(2)).unwrap();
+
+        let outbox = Outbox::open(path).unwrap();
+        assert_eq!(outbox.len(), 3);
+    }
+}